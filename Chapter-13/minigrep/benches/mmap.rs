@@ -0,0 +1,89 @@
+// Compares the ways of searching one big file, all in this process so
+// starting minigrep up doesn't count: the book's `lines().filter()` over the
+// whole file read into a String, a `Searcher` reading it a line at a time
+// (what --no-mmap does), and a `Searcher` searching it whole from memory. Run
+// it with `cargo bench --bench mmap`.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use memmap2::Mmap;
+use minigrep::{Line, Matcher, Searcher, Sink};
+
+const LINES: usize = 400_000;
+
+// Write one big file with the occasional line worth finding
+fn big_file() -> PathBuf {
+    let path = std::env::temp_dir().join(format!("minigrep_bench_mmap_{}.txt", std::process::id()));
+    let mut contents = String::new();
+
+    for line in 0..LINES {
+        if line % 1000 == 0 {
+            contents.push_str("How public, like a frog\n");
+        } else {
+            contents.push_str("To tell your name the livelong day to an admiring bog!\n");
+        }
+    }
+
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn lines_filter(path: &Path) -> usize {
+    let contents = fs::read_to_string(path).unwrap();
+
+    contents.lines().filter(|line| line.contains("frog")).count()
+}
+
+// A sink that only counts what it's told about
+#[derive(Default)]
+struct Count(usize);
+
+impl Sink for Count {
+    fn matched(&mut self, _line: &Line) -> io::Result<bool> {
+        self.0 += 1;
+        Ok(true)
+    }
+}
+
+fn line_at_a_time(matcher: &Matcher, path: &Path) -> usize {
+    let mut count = Count::default();
+    Searcher::new().search_reader(matcher, File::open(path).unwrap(), &mut count).unwrap();
+    count.0
+}
+
+fn mapped(matcher: &Matcher, path: &Path) -> usize {
+    let file = File::open(path).unwrap();
+    // nothing else is writing to the file while we search it
+    let contents = unsafe { Mmap::map(&file).unwrap() };
+
+    let mut count = Count::default();
+    Searcher::new().search_slice(matcher, &contents, &mut count).unwrap();
+    count.0
+}
+
+fn search(c: &mut Criterion) {
+    let path = big_file();
+    let matcher = Matcher::fixed(&["frog"]).unwrap();
+
+    // they'd better all agree before we time them
+    let expected = LINES / 1000;
+    assert_eq!(expected, lines_filter(&path));
+    assert_eq!(expected, line_at_a_time(&matcher, &path));
+    assert_eq!(expected, mapped(&matcher, &path));
+
+    let mut group = c.benchmark_group("big file");
+    group.sample_size(20);
+    group.throughput(Throughput::Bytes(fs::metadata(&path).unwrap().len()));
+    group.bench_function("lines().filter()", |b| b.iter(|| lines_filter(&path)));
+    group.bench_function("search_reader", |b| b.iter(|| line_at_a_time(&matcher, &path)));
+    group.bench_function("search_slice (mmap)", |b| b.iter(|| mapped(&matcher, &path)));
+    group.finish();
+
+    fs::remove_file(&path).unwrap();
+}
+
+criterion_group!(benches, search);
+criterion_main!(benches);
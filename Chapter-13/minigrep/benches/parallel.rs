@@ -0,0 +1,90 @@
+// Compares searching a pile of files one at a time with searching them on
+// a pool of threads the way -j does, all in this process so starting
+// minigrep up doesn't count. Run it with `cargo bench --bench parallel`.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use minigrep::{ordered_map, Line, Matcher, Searcher, Sink};
+
+const FILES: usize = 200;
+const LINES_PER_FILE: usize = 5_000;
+
+// Write FILES files of text to a temporary directory, with the occasional
+// line worth finding
+fn corpus() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("minigrep_bench_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    for file in 0..FILES {
+        let mut contents = String::new();
+
+        for line in 0..LINES_PER_FILE {
+            if line % 1000 == 0 {
+                contents.push_str("How public, like a frog\n");
+            } else {
+                contents.push_str("To tell your name the livelong day to an admiring bog!\n");
+            }
+        }
+
+        fs::write(dir.join(format!("{}.txt", file)), contents).unwrap();
+    }
+
+    dir
+}
+
+// A sink that only counts what it's told about
+#[derive(Default)]
+struct Count(usize);
+
+impl Sink for Count {
+    fn matched(&mut self, _line: &Line) -> io::Result<bool> {
+        self.0 += 1;
+        Ok(true)
+    }
+}
+
+fn count(matcher: &Matcher, path: &Path) -> usize {
+    let mut count = Count::default();
+    Searcher::new().search_path(matcher, path, &mut count).unwrap();
+    count.0
+}
+
+fn one_at_a_time(matcher: &Matcher, paths: &[PathBuf]) -> usize {
+    paths.iter().map(|path| count(matcher, path)).sum()
+}
+
+fn pooled(matcher: &Matcher, paths: &[PathBuf], jobs: usize) -> usize {
+    let mut total = 0;
+
+    ordered_map(paths, jobs, |path| count(matcher, path), |found| {
+        total += found;
+        Ok::<(), ()>(())
+    }).unwrap();
+
+    total
+}
+
+fn search(c: &mut Criterion) {
+    let dir = corpus();
+    let paths: Vec<PathBuf> = (0..FILES).map(|file| dir.join(format!("{}.txt", file))).collect();
+    let matcher = Matcher::fixed(&["frog"]).unwrap();
+
+    // they'd better agree before we time them
+    let expected = FILES * LINES_PER_FILE / 1000;
+    assert_eq!(expected, one_at_a_time(&matcher, &paths));
+    assert_eq!(expected, pooled(&matcher, &paths, 4));
+
+    let mut group = c.benchmark_group("search");
+    group.sample_size(20);
+    group.bench_function("one at a time", |b| b.iter(|| one_at_a_time(&matcher, &paths)));
+    group.bench_function("-j 4", |b| b.iter(|| pooled(&matcher, &paths, 4)));
+    group.finish();
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+criterion_group!(benches, search);
+criterion_main!(benches);
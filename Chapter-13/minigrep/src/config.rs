@@ -0,0 +1,1232 @@
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::thread;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use regex::{Regex, RegexBuilder};
+
+pub const USAGE: &str = "\
+Usage: minigrep [OPTIONS] QUERY [FILE...]
+       minigrep [OPTIONS] -e QUERY... [FILE...]
+       minigrep -r [OPTIONS] QUERY [PATH...]
+       minigrep --interactive [OPTIONS] FILE...
+       minigrep --follow [OPTIONS] QUERY FILE...
+
+With no FILE, or when FILE is -, read standard input.
+
+Options:
+  -e, --regexp QUERY   search for QUERY; use more than once to search for
+                       any of several queries
+  -f, --file FILE      search for every line of FILE as a query
+  -E, --regex          treat queries as regular expressions
+  -i, --ignore-case    match regardless of case (or set CASE_INSENSITIVE)
+  -S, --smart-case     match regardless of case unless a query has
+                       uppercase letters in it
+  -s, --case-sensitive match case, even if -i or -S was given before
+  -v, --invert-match   print the lines that don't match
+      --no-invert-match
+                       print the lines that match, even if -v was given
+                       before
+  -n, --line-number    print the line number before each line
+  -N, --no-line-number don't, even if -n was given before
+  -c, --count          only print how many lines matched
+  -l, --files-with-matches
+                       only print the names of files with a match
+  -L, --files-without-match
+                       only print the names of files without a match
+  -m, --max-count N    stop reading a file after N matching lines
+  -q, --quiet          print nothing, and stop at the first match
+  -o, --only-matching  print just the matched parts of each line
+      --json           print results as JSON, one message per line
+      --fuzzy          rank lines by how closely they match, allowing
+                       for typos and missing letters, and print the best
+                       with their scores
+      --top N          with --fuzzy, how many lines to print (default 10)
+      --interactive    search FILEs as you type the query, in a full screen
+                       view of the matches (starting from the -e QUERY, if
+                       there is one)
+      --follow         keep FILEs open like `tail -f`, printing matching
+                       lines as they're added (following files that are
+                       replaced or truncated, as logs are when rotated)
+      --replace TEXT   print matching lines with each match replaced by
+                       TEXT, which can use $0 for the match and $1, $name
+                       etc. for a regex's capture groups
+      --in-place       with --replace, rewrite the files instead of
+                       printing anything
+      --backup SUFFIX  with --in-place, keep the originals with SUFFIX on
+                       the end of their name
+      --color[=WHEN]   highlight matches: auto (the default when stdout is
+                       a terminal), always or never
+  -A, --after-context N
+                       print N lines after each match
+  -B, --before-context N
+                       print N lines before each match
+  -C, --context N      print N lines before and after each match
+  -w, --word-regexp    only match whole words
+  -r, --recursive      search directories, skipping hidden, ignored and
+                       binary files (searches . if no PATH is given)
+      --include GLOB   only search files whose name matches GLOB
+  -z, --decompress     decompress standard input too, and fail on inputs
+                       that aren't compressed (gzip and zstd files are
+                       always decompressed)
+  -j, --jobs N         search N files at once (defaults to the number of
+                       CPUs)
+      --no-mmap        always read files a line at a time, rather than
+                       searching them whole from memory when we can
+      --no-config      ignore MINIGREP_CONFIG
+  -h, --help           print this message
+  -V, --version        print the version
+
+Use -- to stop option parsing, e.g. `minigrep -- -v poem.txt`.
+
+Default options can be kept in a file named by MINIGREP_CONFIG, one
+argument per line, like `--max-count=3` or `-A` then `2`. Blank lines and
+lines starting with # are skipped. Options on the command line win over
+ones from the file, and the file wins over CASE_INSENSITIVE.
+
+The exit status is 0 if any line was selected (or with -L, any file), 1 if
+none were, and otherwise says what went wrong: 2 for bad options, 3 for an
+invalid pattern, 4 if a file couldn't be read or written, and 5 if one
+wasn't in the format we expected (like a -f FILE that isn't UTF-8).";
+
+// When to highlight matches, as in --color=WHEN
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    // only when printing to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+pub struct Config {
+    // what to search for; a line matches if any of them do
+    pub patterns: Vec<String>,
+    pub filenames: Vec<String>,
+    pub case_sensitive: bool,
+    // `Some` when we were asked to treat the patterns as regular expressions
+    // (or match whole words, which needs them). Each pattern gets a capture
+    // group of its own, named by `pattern_group`.
+    pub regex: Option<Regex>,
+    // whether the queries are regexes (-E) rather than plain strings, and
+    // whether they only match whole words (-w). Both are already part of
+    // `regex`.
+    pub regex_syntax: bool,
+    pub word_regexp: bool,
+    pub invert_match: bool,
+    pub line_number: bool,
+    pub count: bool,
+    pub files_with_matches: bool,
+    pub files_without_match: bool,
+    pub max_count: Option<usize>,
+    pub quiet: bool,
+    pub only_matching: bool,
+    pub json: bool,
+    // with --fuzzy, how many of the best-scoring lines to print
+    pub fuzzy: Option<usize>,
+    pub interactive: bool,
+    pub follow: bool,
+    // `Some` with --replace, to print (or with `in_place`, write) lines
+    // with their matches replaced
+    pub replace: Option<String>,
+    pub in_place: bool,
+    pub backup: Option<String>,
+    pub color: ColorChoice,
+    pub before_context: usize,
+    pub after_context: usize,
+    pub recursive: bool,
+    // how many files to search at once
+    pub jobs: usize,
+    // whether files can be memory mapped and searched all at once
+    pub mmap: bool,
+    // whether every input has to be compressed, stdin included
+    pub decompress: bool,
+    // `Some` when --include limited which file names we search
+    pub include: Option<GlobSet>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    MissingQuery,
+    InvalidPattern(regex::Error),
+    PatternFile(String, io::Error),
+    ConfigFile(String, io::Error),
+    // a config file with something other than options in it
+    ConfigFileArg(String, String),
+    UnknownFlag(String),
+    MissingValue(String),
+    InvalidValue(String, String),
+    InvalidGlob(globset::Error),
+    Conflict(&'static str, &'static str),
+    Requires(&'static str, &'static str),
+    // not really errors, but they stop us from searching all the same
+    Help,
+    Version,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::MissingQuery => write!(f, "Didn't get a query string"),
+            ConfigError::InvalidPattern(e) => write!(f, "Invalid regular expression: {}", e),
+            ConfigError::PatternFile(path, e) => write!(f, "Can't read queries from {}: {}", path, e),
+            ConfigError::ConfigFile(path, e) => write!(f, "Can't read config file {}: {}", path, e),
+            ConfigError::ConfigFileArg(path, arg) => {
+                write!(f, "Config file {} can only set options, not {}", path, arg)
+            },
+            ConfigError::UnknownFlag(flag) => write!(f, "Unknown option {}", flag),
+            ConfigError::MissingValue(flag) => write!(f, "Option {} needs a value", flag),
+            ConfigError::InvalidValue(flag, value) => write!(f, "Invalid value {:?} for {}", value, flag),
+            ConfigError::InvalidGlob(e) => write!(f, "Invalid glob: {}", e),
+            ConfigError::Conflict(a, b) => write!(f, "Options {} and {} can't be used together", a, b),
+            ConfigError::Requires(a, b) => write!(f, "Option {} only works with {}", a, b),
+            ConfigError::Help => write!(f, "{}", USAGE),
+            ConfigError::Version => write!(f, "minigrep {}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::InvalidPattern(e) => Some(e),
+            ConfigError::PatternFile(_, e) => Some(e),
+            ConfigError::ConfigFile(_, e) => Some(e),
+            ConfigError::InvalidGlob(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Flags {
+    patterns: Vec<String>,
+    pattern_files: Vec<String>,
+    regex: bool,
+    ignore_case: bool,
+    smart_case: bool,
+    // -s, which turns the other two back off
+    case_sensitive: bool,
+    invert_match: bool,
+    line_number: bool,
+    count: bool,
+    files_with_matches: bool,
+    files_without_match: bool,
+    max_count: Option<usize>,
+    quiet: bool,
+    only_matching: bool,
+    json: bool,
+    fuzzy: bool,
+    interactive: bool,
+    follow: bool,
+    top: Option<usize>,
+    replace: Option<String>,
+    in_place: bool,
+    backup: Option<String>,
+    color: Option<ColorChoice>,
+    before_context: Option<usize>,
+    after_context: Option<usize>,
+    context: usize,
+    jobs: Option<usize>,
+    no_mmap: bool,
+    word_regexp: bool,
+    recursive: bool,
+    include: Vec<String>,
+    decompress: bool,
+}
+
+impl Flags {
+    // Parse `args`, setting whatever they ask for and giving back the ones
+    // that aren't options
+    fn parse<I: Iterator<Item = String>>(&mut self, mut args: I) -> Result<Vec<String>, ConfigError> {
+        let mut positional = Vec::new();
+        let mut only_positional = false;
+
+        while let Some(arg) = args.next() {
+            if only_positional || arg == "-" || !arg.starts_with('-') {
+                positional.push(arg);
+            } else if arg == "--" {
+                only_positional = true;
+            } else if arg.starts_with("--") {
+                // long options with values come as --name=value or --name value
+                let (name, inline) = match arg.find('=') {
+                    Some(eq) => (&arg[..eq], Some(arg[eq + 1..].to_string())),
+                    None => (arg.as_str(), None),
+                };
+
+                if name == "--color" || name == "--colour" {
+                    // unlike the others the value is optional, so it has
+                    // to be stuck on with =
+                    self.color = Some(match inline.as_deref() {
+                        None | Some("auto") => ColorChoice::Auto,
+                        Some("always") => ColorChoice::Always,
+                        Some("never") => ColorChoice::Never,
+                        Some(value) => return Err(ConfigError::InvalidValue(name.to_string(), value.to_string())),
+                    });
+                    continue;
+                }
+
+                if is_long_value_flag(name) {
+                    let value = match inline.or_else(|| args.next()) {
+                        Some(value) => value,
+                        None => return Err(ConfigError::MissingValue(arg)),
+                    };
+                    self.set_value(name, &value)?;
+                } else {
+                    self.set_long(&arg)?;
+                }
+            } else {
+                // short flags can be bunched up, like -in, and the last one
+                // may take a value either stuck on (-A3) or as the next arg
+                let bunch: Vec<char> = arg.chars().skip(1).collect();
+
+                for (i, &flag) in bunch.iter().enumerate() {
+                    let long = match short_value_flag(flag) {
+                        Some(long) => long,
+                        None => {
+                            self.set_short(flag)?;
+                            continue;
+                        },
+                    };
+
+                    let rest: String = bunch[i + 1..].iter().collect();
+                    let value = if !rest.is_empty() {
+                        rest
+                    } else {
+                        match args.next() {
+                            Some(value) => value,
+                            None => return Err(ConfigError::MissingValue(format!("-{}", flag))),
+                        }
+                    };
+
+                    self.set_value(long, &value)?;
+                    break;
+                }
+            }
+        }
+
+        Ok(positional)
+    }
+
+    fn set_short(&mut self, flag: char) -> Result<(), ConfigError> {
+        match flag {
+            'E' => self.regex = true,
+            'i' => {
+                self.ignore_case = true;
+                self.case_sensitive = false;
+            },
+            'S' => {
+                self.smart_case = true;
+                self.case_sensitive = false;
+            },
+            's' => {
+                self.ignore_case = false;
+                self.smart_case = false;
+                self.case_sensitive = true;
+            },
+            'v' => self.invert_match = true,
+            'n' => self.line_number = true,
+            'N' => self.line_number = false,
+            'c' => self.count = true,
+            'l' => self.files_with_matches = true,
+            'L' => self.files_without_match = true,
+            'q' => self.quiet = true,
+            'o' => self.only_matching = true,
+            'w' => self.word_regexp = true,
+            'r' => self.recursive = true,
+            'z' => self.decompress = true,
+            'h' => return Err(ConfigError::Help),
+            'V' => return Err(ConfigError::Version),
+            _ => return Err(ConfigError::UnknownFlag(format!("-{}", flag))),
+        }
+
+        Ok(())
+    }
+
+    fn set_long(&mut self, flag: &str) -> Result<(), ConfigError> {
+        let short = match flag {
+            "--regex" => 'E',
+            "--ignore-case" => 'i',
+            "--smart-case" => 'S',
+            "--case-sensitive" => 's',
+            "--invert-match" => 'v',
+            "--line-number" => 'n',
+            "--no-line-number" => 'N',
+            "--count" => 'c',
+            "--files-with-matches" => 'l',
+            "--files-without-match" => 'L',
+            "--quiet" | "--silent" => 'q',
+            "--only-matching" => 'o',
+            // long only, so there's no short flag to hand over to
+            "--json" => {
+                self.json = true;
+                return Ok(());
+            },
+            "--no-invert-match" => {
+                self.invert_match = false;
+                return Ok(());
+            },
+            "--in-place" => {
+                self.in_place = true;
+                return Ok(());
+            },
+            "--fuzzy" => {
+                self.fuzzy = true;
+                return Ok(());
+            },
+            "--interactive" => {
+                self.interactive = true;
+                return Ok(());
+            },
+            "--follow" => {
+                self.follow = true;
+                return Ok(());
+            },
+            "--no-mmap" => {
+                self.no_mmap = true;
+                return Ok(());
+            },
+            // already dealt with by Config::new
+            "--no-config" => return Ok(()),
+            "--word-regexp" => 'w',
+            "--recursive" => 'r',
+            "--decompress" => 'z',
+            "--help" => 'h',
+            "--version" => 'V',
+            _ => return Err(ConfigError::UnknownFlag(flag.to_string())),
+        };
+
+        self.set_short(short)
+    }
+
+    // Set one of the VALUE_FLAGS, by its long name
+    fn set_value(&mut self, flag: &str, value: &str) -> Result<(), ConfigError> {
+        match flag {
+            "--regexp" => self.patterns.push(value.to_string()),
+            "--file" => self.pattern_files.push(value.to_string()),
+            "--include" => self.include.push(value.to_string()),
+            "--replace" => self.replace = Some(value.to_string()),
+            "--backup" => self.backup = Some(value.to_string()),
+            _ => return self.set_number(flag, value),
+        }
+
+        Ok(())
+    }
+
+    fn set_number(&mut self, flag: &str, value: &str) -> Result<(), ConfigError> {
+        let invalid = || ConfigError::InvalidValue(flag.to_string(), value.to_string());
+        let n = value.parse::<usize>().map_err(|_| invalid())?;
+
+        match flag {
+            "--after-context" => self.after_context = Some(n),
+            "--before-context" => self.before_context = Some(n),
+            "--context" => self.context = n,
+            "--max-count" => self.max_count = Some(n),
+            "--top" => self.top = Some(n),
+            // we can't search with no threads at all
+            "--jobs" if n == 0 => return Err(invalid()),
+            "--jobs" => self.jobs = Some(n),
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    // Whether the flag named in a ConfigError is set
+    fn is_set(&self, flag: &str) -> bool {
+        match flag {
+            "-E" => self.regex,
+            "-w" => self.word_regexp,
+            "-v" => self.invert_match,
+            "-c" => self.count,
+            "-l" => self.files_with_matches,
+            "-L" => self.files_without_match,
+            "-q" => self.quiet,
+            "-o" => self.only_matching,
+            "-m" => self.max_count.is_some(),
+            "-j" => self.jobs.is_some(),
+            "-r" => self.recursive,
+            "-z" => self.decompress,
+            "-A/-B/-C" => self.context > 0 || self.before_context.is_some() || self.after_context.is_some(),
+            "--json" => self.json,
+            "--fuzzy" => self.fuzzy,
+            "--top" => self.top.is_some(),
+            "--interactive" => self.interactive,
+            "--follow" => self.follow,
+            "--replace" => self.replace.is_some(),
+            "--in-place" => self.in_place,
+            "--backup" => self.backup.is_some(),
+            _ => unreachable!(),
+        }
+    }
+
+    // Unset the flag named in a ConfigError, as if it was never given
+    fn clear(&mut self, flag: &str) {
+        match flag {
+            "-E" => self.regex = false,
+            "-w" => self.word_regexp = false,
+            "-v" => self.invert_match = false,
+            "-c" => self.count = false,
+            "-l" => self.files_with_matches = false,
+            "-L" => self.files_without_match = false,
+            "-q" => self.quiet = false,
+            "-o" => self.only_matching = false,
+            "-m" => self.max_count = None,
+            "-j" => self.jobs = None,
+            "-r" => self.recursive = false,
+            "-z" => self.decompress = false,
+            "-A/-B/-C" => {
+                self.context = 0;
+                self.before_context = None;
+                self.after_context = None;
+            },
+            "--json" => self.json = false,
+            "--fuzzy" => self.fuzzy = false,
+            "--top" => self.top = None,
+            "--interactive" => self.interactive = false,
+            "--follow" => self.follow = false,
+            "--replace" => self.replace = None,
+            "--in-place" => self.in_place = false,
+            "--backup" => self.backup = None,
+            _ => unreachable!(),
+        }
+    }
+}
+
+// The flags that pick what gets printed, only one of which is worth
+// keeping from the config file
+const MODES: &[&str] = &["-c", "-l", "-L", "-q", "--json", "--fuzzy", "--interactive", "--follow"];
+
+// `flags` can't have both `a` and `b`. If one of them only came from the
+// config file the command line wins and it's dropped, but if the command
+// line (or the config file itself) asked for both, that's an error.
+fn settle(flags: &mut Flags, cli: &Flags, a: &'static str, b: &'static str) -> Result<(), ConfigError> {
+    if !flags.is_set(a) || !flags.is_set(b) {
+        return Ok(());
+    }
+
+    if cli.is_set(b) && !cli.is_set(a) {
+        flags.clear(a);
+    } else if cli.is_set(a) && !cli.is_set(b) {
+        flags.clear(b);
+    } else {
+        return Err(ConfigError::Conflict(a, b));
+    }
+
+    Ok(())
+}
+
+// `a` only works with `b`, which a config file setting `a` just in case
+// doesn't have to worry about
+fn require(flags: &mut Flags, cli: &Flags, a: &'static str, b: &'static str) -> Result<(), ConfigError> {
+    if !flags.is_set(a) || flags.is_set(b) {
+        return Ok(());
+    }
+
+    if cli.is_set(a) {
+        return Err(ConfigError::Requires(a, b));
+    }
+
+    flags.clear(a);
+    Ok(())
+}
+
+// Options that take a value, by their short name (if they have one) and
+// their long name
+const VALUE_FLAGS: &[(Option<char>, &str)] = &[
+    (Some('e'), "--regexp"),
+    (Some('f'), "--file"),
+    (None, "--include"),
+    (None, "--replace"),
+    (None, "--backup"),
+    (Some('A'), "--after-context"),
+    (Some('B'), "--before-context"),
+    (Some('C'), "--context"),
+    (Some('m'), "--max-count"),
+    (None, "--top"),
+    (Some('j'), "--jobs"),
+];
+
+fn short_value_flag(flag: char) -> Option<&'static str> {
+    VALUE_FLAGS.iter().find(|(short, _)| *short == Some(flag)).map(|(_, long)| *long)
+}
+
+fn is_long_value_flag(flag: &str) -> bool {
+    VALUE_FLAGS.iter().any(|(_, long)| *long == flag)
+}
+
+// Whether a query has any uppercase letters, for --smart-case. In a regex,
+// escapes like \W or \S don't count.
+fn has_uppercase(pattern: &str, regex: bool) -> bool {
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        if regex && c == '\\' {
+            chars.next();
+        } else if c.is_uppercase() {
+            return true;
+        }
+    }
+
+    false
+}
+
+// The name of the capture group holding pattern `i` in `Config::regex`
+pub(crate) fn pattern_group(i: usize) -> String {
+    format!("minigrep_pattern_{}", i)
+}
+
+// Join regexes into one that matches any of them, each in a capture group
+// named by `pattern_group` so we can tell which one matched
+pub(crate) fn alternation<P: AsRef<str>>(patterns: &[P]) -> String {
+    let alternatives: Vec<String> = patterns.iter()
+        .enumerate()
+        .map(|(i, pattern)| format!("(?P<{}>{})", pattern_group(i), pattern.as_ref()))
+        .collect();
+
+    alternatives.join("|")
+}
+
+// Options read from the file MINIGREP_CONFIG points at
+pub(crate) struct Defaults {
+    path: String,
+    args: Vec<String>,
+}
+
+// One argument per line, like `-e` files but for options. Lines are trimmed,
+// so `--context=2  ` is fine, and blank lines and # comments are skipped.
+fn read_defaults(path: String) -> Result<Defaults, ConfigError> {
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) => return Err(ConfigError::ConfigFile(path, e)),
+    };
+
+    let args = contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect();
+
+    Ok(Defaults { path, args })
+}
+
+impl Config {
+    pub fn new<I>(mut args: I) -> Result<Config, ConfigError>
+    where
+        I: Iterator<Item = String>,
+    {
+        // let's skip over the first argument which is always
+        // the filename itself
+        args.next();
+
+        let args: Vec<String> = args.collect();
+
+        // --no-config only counts on the command line itself
+        let no_config = args.iter().take_while(|arg| *arg != "--").any(|arg| arg == "--no-config");
+
+        let defaults = match env::var("MINIGREP_CONFIG") {
+            Ok(path) if !path.is_empty() && !no_config => Some(read_defaults(path)?),
+            _ => None,
+        };
+
+        Config::parse(defaults, args, env::var("CASE_INSENSITIVE").is_ok())
+    }
+
+    // Everything `new` does, minus looking at the environment: options from
+    // `defaults` come first so `args` can override them, and
+    // `env_ignore_case` only counts if neither says anything about case
+    pub(crate) fn parse(defaults: Option<Defaults>, args: Vec<String>, env_ignore_case: bool) -> Result<Config, ConfigError> {
+        let mut flags = Flags::default();
+
+        if let Some(Defaults { path, args }) = defaults {
+            // a query or file in there would push the command line's query
+            // along to being a file
+            if let Some(arg) = flags.parse(args.into_iter())?.into_iter().next() {
+                return Err(ConfigError::ConfigFileArg(path, format!("{:?}", arg)));
+            }
+
+            if !flags.patterns.is_empty() || !flags.pattern_files.is_empty() {
+                return Err(ConfigError::ConfigFileArg(path, "queries".to_string()));
+            }
+        }
+
+        // the command line on its own too, so we can tell what the config
+        // file set from what was asked for this time
+        let mut cli = Flags::default();
+        cli.parse(args.iter().cloned())?;
+
+        let positional = flags.parse(args.into_iter())?;
+
+        // picking what to print on the command line replaces whatever the
+        // config file picked
+        if MODES.iter().any(|&mode| cli.is_set(mode)) {
+            for &mode in MODES {
+                if !cli.is_set(mode) {
+                    flags.clear(mode);
+                }
+            }
+        }
+
+        settle(&mut flags, &cli, "-l", "-L")?;
+
+        // JSON is only for lines, not counts or file names
+        for &flag in &["-c", "-l", "-L"] {
+            settle(&mut flags, &cli, "--json", flag)?;
+        }
+
+        // fuzzy queries aren't patterns, and their lines are ranked rather
+        // than just selected
+        let fuzzy_conflict = [
+            "-E", "-w", "-v", "-c", "-l", "-L", "-o", "--json", "--replace", "-m", "-q", "-j",
+        ];
+
+        for &flag in &fuzzy_conflict {
+            settle(&mut flags, &cli, "--fuzzy", flag)?;
+        }
+
+        // the interactive view only has room for whole matching lines, and
+        // its query is the one line being edited
+        let interactive_conflict = [
+            "-v", "-c", "-l", "-L", "-q", "--json", "--replace", "--in-place", "--fuzzy", "-o", "-m", "-A/-B/-C",
+        ];
+
+        for &flag in &interactive_conflict {
+            settle(&mut flags, &cli, "--interactive", flag)?;
+        }
+
+        // queries can only come from the command line, so there's nothing
+        // to settle
+        if flags.interactive && !flags.pattern_files.is_empty() {
+            return Err(ConfigError::Conflict("--interactive", "-f"));
+        }
+
+        if flags.interactive && flags.patterns.len() > 1 {
+            return Err(ConfigError::Conflict("--interactive", "more than one -e"));
+        }
+
+        // following never finishes a file, so only lines can be printed as
+        // they come, and there's no end to count or rank them at
+        let follow_conflict = [
+            "-c", "-l", "-L", "-q", "-m", "--json", "--fuzzy", "--interactive", "--in-place", "-r", "-z",
+        ];
+
+        for &flag in &follow_conflict {
+            settle(&mut flags, &cli, "--follow", flag)?;
+        }
+
+        // we only rewrite the lines that match, and don't write compressed
+        // files back out
+        settle(&mut flags, &cli, "--in-place", "-v")?;
+        settle(&mut flags, &cli, "--in-place", "-z")?;
+
+        require(&mut flags, &cli, "--top", "--fuzzy")?;
+        require(&mut flags, &cli, "--in-place", "--replace")?;
+        require(&mut flags, &cli, "--backup", "--in-place")?;
+
+        let mut positional = positional.into_iter();
+
+        // without -e or -f the query comes before the files, except that
+        // --interactive can start without one
+        let mut patterns = if flags.interactive {
+            flags.patterns
+        } else if flags.patterns.is_empty() && flags.pattern_files.is_empty() {
+            match positional.next() {
+                Some(arg) => vec![arg],
+                None => return Err(ConfigError::MissingQuery),
+            }
+        } else {
+            flags.patterns
+        };
+
+        for path in &flags.pattern_files {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| ConfigError::PatternFile(path.clone(), e))?;
+
+            patterns.extend(contents.lines().map(String::from));
+        }
+
+        let mut filenames: Vec<String> = positional.collect();
+
+        // stdin is where we read the keyboard from
+        if flags.interactive && filenames.is_empty() && !flags.recursive {
+            return Err(ConfigError::Requires("--interactive", "a FILE"));
+        }
+
+        // there's nothing to follow on stdin that reading it doesn't do
+        // already
+        if flags.follow && (filenames.is_empty() || filenames.iter().any(|name| name == "-")) {
+            return Err(ConfigError::Requires("--follow", "a FILE"));
+        }
+
+        // like grep, -r searches where we are and otherwise we read stdin
+        if filenames.is_empty() {
+            let default = if flags.recursive { "." } else { "-" };
+            filenames.push(default.to_string());
+        }
+
+        let include = if flags.include.is_empty() {
+            None
+        } else {
+            let mut globs = GlobSetBuilder::new();
+
+            for glob in &flags.include {
+                globs.add(Glob::new(glob).map_err(ConfigError::InvalidGlob)?);
+            }
+
+            Some(globs.build().map_err(ConfigError::InvalidGlob)?)
+        };
+
+        // the flags win, so the environment variable only counts if none
+        // of -i, -S and -s was given (whichever came last is still set)
+        let case_given = flags.ignore_case || flags.smart_case || flags.case_sensitive;
+        let ignore_case = flags.ignore_case || (env_ignore_case && !case_given);
+        let case_sensitive = if ignore_case {
+            false
+        } else if flags.smart_case {
+            let regex = flags.regex;
+            patterns.iter().any(|pattern| has_uppercase(pattern, regex))
+        } else {
+            true
+        };
+
+        // the regex engine knows Unicode case folding, so it does our
+        // case-insensitive matching for plain strings too
+        let regex = if flags.regex || flags.word_regexp || !case_sensitive {
+            let mut alternatives = Vec::new();
+
+            for pattern in &patterns {
+                let mut pattern = if flags.regex {
+                    pattern.clone()
+                } else {
+                    regex::escape(pattern)
+                };
+
+                if flags.word_regexp {
+                    pattern = format!(r"\b(?:{})\b", pattern);
+                }
+
+                alternatives.push(pattern);
+            }
+
+            let regex = RegexBuilder::new(&alternation(&alternatives))
+                .case_insensitive(!case_sensitive)
+                .build()
+                .map_err(ConfigError::InvalidPattern)?;
+
+            Some(regex)
+        } else {
+            None
+        };
+
+        Ok(Config {
+            patterns,
+            filenames,
+            case_sensitive,
+            regex,
+            regex_syntax: flags.regex,
+            word_regexp: flags.word_regexp,
+            invert_match: flags.invert_match,
+            line_number: flags.line_number,
+            count: flags.count,
+            files_with_matches: flags.files_with_matches,
+            files_without_match: flags.files_without_match,
+            max_count: flags.max_count,
+            quiet: flags.quiet,
+            only_matching: flags.only_matching,
+            json: flags.json,
+            fuzzy: if flags.fuzzy { Some(flags.top.unwrap_or(10)) } else { None },
+            interactive: flags.interactive,
+            follow: flags.follow,
+            replace: flags.replace,
+            in_place: flags.in_place,
+            backup: flags.backup,
+            color: flags.color.unwrap_or(ColorChoice::Auto),
+            // -A and -B win over -C whichever order they come in
+            before_context: flags.before_context.unwrap_or(flags.context),
+            after_context: flags.after_context.unwrap_or(flags.context),
+            recursive: flags.recursive,
+            jobs: flags.jobs.unwrap_or_else(|| {
+                thread::available_parallelism().map_or(1, |n| n.get())
+            }),
+            mmap: !flags.no_mmap,
+            include,
+            decompress: flags.decompress,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, ConfigError> {
+        Config::parse(None, strings(args), false)
+    }
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn with_defaults(defaults: &[&str], args: &[&str], env_ignore_case: bool) -> Result<Config, ConfigError> {
+        let defaults = Defaults {
+            path: "minigreprc".to_string(),
+            args: strings(defaults),
+        };
+
+        Config::parse(Some(defaults), strings(args), env_ignore_case)
+    }
+
+    #[test]
+    fn regex_flag_compiles_query() {
+        let config = parse(&["-E", "fa(st|r)", "poem.txt"]).unwrap();
+
+        assert_eq!(vec!["fa(st|r)"], config.patterns);
+        assert_eq!(vec!["poem.txt"], config.filenames);
+        assert!(config.regex.unwrap().is_match("fast"));
+    }
+
+    #[test]
+    fn invalid_regex_is_a_config_error() {
+        match parse(&["--regex", "fa(st", "poem.txt"]) {
+            Err(ConfigError::InvalidPattern(_)) => (),
+            _ => panic!("expected an invalid pattern error"),
+        }
+    }
+
+    #[test]
+    fn fixed_strings_by_default() {
+        let config = parse(&["fa(st", "poem.txt"]).unwrap();
+
+        assert!(config.regex.is_none());
+    }
+
+    #[test]
+    fn smart_case_looks_for_uppercase() {
+        assert!(!parse(&["-S", "frog"]).unwrap().case_sensitive);
+        assert!(parse(&["-S", "Frog"]).unwrap().case_sensitive);
+        assert!(parse(&["--smart-case", "-e", "frog", "-e", "ÉTANG"]).unwrap().case_sensitive);
+
+        // \W is a regex escape rather than an uppercase W
+        assert!(!parse(&["-SE", r"fr\Wg"]).unwrap().case_sensitive);
+        assert!(parse(&["-S", r"fr\Wg"]).unwrap().case_sensitive);
+
+        // -i always wins
+        assert!(!parse(&["-S", "-i", "Frog"]).unwrap().case_sensitive);
+    }
+
+    #[test]
+    fn bunched_short_flags_and_many_files() {
+        let config = parse(&["-invc", "frog", "a.txt", "b.txt"]).unwrap();
+
+        assert!(!config.case_sensitive);
+        assert!(config.invert_match);
+        assert!(config.line_number);
+        assert!(config.count);
+        assert_eq!(vec!["a.txt", "b.txt"], config.filenames);
+    }
+
+    #[test]
+    fn double_dash_ends_options() {
+        let config = parse(&["-n", "--", "-v", "-c"]).unwrap();
+
+        assert_eq!(vec!["-v"], config.patterns);
+        assert_eq!(vec!["-c"], config.filenames);
+        assert!(config.line_number);
+        assert!(!config.invert_match);
+    }
+
+    #[test]
+    fn word_regexp_escapes_fixed_strings() {
+        let config = parse(&["-w", "a.b", "poem.txt"]).unwrap();
+        let regex = config.regex.unwrap();
+
+        assert!(regex.is_match("x a.b y"));
+        assert!(!regex.is_match("xa.b"));
+        assert!(!regex.is_match("a-b"));
+    }
+
+    #[test]
+    fn reads_stdin_without_files() {
+        let config = parse(&["frog"]).unwrap();
+
+        assert_eq!(vec!["-"], config.filenames);
+    }
+
+    #[test]
+    fn recursive_defaults_to_current_dir() {
+        let config = parse(&["-r", "--include", "*.rs", "--include=*.toml", "fn"]).unwrap();
+        let include = config.include.unwrap();
+
+        assert!(config.recursive);
+        assert_eq!(vec!["."], config.filenames);
+        assert!(include.is_match("lib.rs"));
+        assert!(include.is_match("Cargo.toml"));
+        assert!(!include.is_match("poem.txt"));
+    }
+
+    #[test]
+    fn include_needs_a_valid_glob() {
+        assert!(matches!(parse(&["-r", "fn", "--include"]), Err(ConfigError::MissingValue(_))));
+        assert!(matches!(parse(&["-r", "--include", "a[", "fn"]), Err(ConfigError::InvalidGlob(_))));
+    }
+
+    #[test]
+    fn context_flags_take_values() {
+        let config = parse(&["-nA2", "-C", "5", "frog", "poem.txt"]).unwrap();
+
+        assert!(config.line_number);
+        assert_eq!(2, config.after_context);
+        assert_eq!(5, config.before_context);
+
+        let config = parse(&["--before-context=1", "--after-context", "3", "frog"]).unwrap();
+
+        assert_eq!(1, config.before_context);
+        assert_eq!(3, config.after_context);
+
+        assert!(matches!(parse(&["-A", "lots", "frog"]), Err(ConfigError::InvalidValue(..))));
+        assert!(matches!(parse(&["frog", "-B"]), Err(ConfigError::MissingValue(_))));
+    }
+
+    #[test]
+    fn patterns_from_flags_and_files() {
+        let path = std::env::temp_dir().join(format!("minigrep_patterns_{}", std::process::id()));
+        fs::write(&path, "toad\nnewt\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        // once there's a -e or -f, every positional argument is a file
+        let config = parse(&["-e", "frog", "-f", path, "--regexp=-v", "poem.txt"]).unwrap();
+
+        assert_eq!(vec!["frog", "-v", "toad", "newt"], config.patterns);
+        assert_eq!(vec!["poem.txt"], config.filenames);
+
+        fs::remove_file(path).unwrap();
+
+        assert!(matches!(parse(&["-f", path]), Err(ConfigError::PatternFile(..))));
+    }
+
+    #[test]
+    fn in_place_needs_a_replacement() {
+        let config = parse(&["--replace=toad", "--in-place", "--backup", ".bak", "frog", "poem.txt"]).unwrap();
+
+        assert_eq!(Some("toad".to_string()), config.replace);
+        assert!(config.in_place);
+        assert_eq!(Some(".bak".to_string()), config.backup);
+
+        assert!(matches!(parse(&["--in-place", "frog"]), Err(ConfigError::Requires(..))));
+        assert!(matches!(parse(&["--replace", "toad", "--backup=~", "frog"]), Err(ConfigError::Requires(..))));
+        assert!(matches!(parse(&["--replace", "toad", "--in-place", "-v", "frog"]), Err(ConfigError::Conflict(..))));
+        assert!(matches!(parse(&["--replace", "toad", "--in-place", "-z", "frog"]), Err(ConfigError::Conflict(..))));
+    }
+
+    #[test]
+    fn fuzzy_shows_the_top_ten_by_default() {
+        assert_eq!(Some(10), parse(&["--fuzzy", "frgo"]).unwrap().fuzzy);
+        assert_eq!(Some(3), parse(&["--fuzzy", "--top=3", "frgo"]).unwrap().fuzzy);
+        assert_eq!(None, parse(&["frog"]).unwrap().fuzzy);
+
+        assert!(matches!(parse(&["--top", "3", "frog"]), Err(ConfigError::Requires(..))));
+        assert!(matches!(parse(&["--fuzzy", "-E", "fr.g"]), Err(ConfigError::Conflict("--fuzzy", "-E"))));
+        assert!(matches!(parse(&["--fuzzy", "-v", "frog"]), Err(ConfigError::Conflict(..))));
+
+        // it reads everything, one file at a time, and prints the best lines
+        for &flag in &["-m1", "-q", "-j2"] {
+            assert!(matches!(parse(&["--fuzzy", flag, "frog"]), Err(ConfigError::Conflict("--fuzzy", _))));
+        }
+    }
+
+    #[test]
+    fn interactive_needs_files_but_not_a_query() {
+        let config = parse(&["--interactive", "poem.txt"]).unwrap();
+        assert!(config.interactive);
+        assert!(config.patterns.is_empty());
+        assert_eq!(vec!["poem.txt"], config.filenames);
+
+        let config = parse(&["--interactive", "-e", "frog", "poem.txt", "output.txt"]).unwrap();
+        assert_eq!(vec!["frog"], config.patterns);
+        assert_eq!(vec!["poem.txt", "output.txt"], config.filenames);
+
+        assert!(matches!(parse(&["--interactive"]), Err(ConfigError::Requires(..))));
+        assert!(matches!(parse(&["--interactive", "-c", "poem.txt"]), Err(ConfigError::Conflict(..))));
+
+        // nothing it would quietly ignore, queries included
+        let ignored: [&[&str]; 6] = [&["-o"], &["-m1"], &["-C2"], &["-A", "1"], &["-f", "poem.txt"], &["-e", "a", "-e", "b"]];
+
+        for args in &ignored {
+            let args: Vec<&str> = ["--interactive"].iter().chain(args.iter()).chain(["poem.txt"].iter()).cloned().collect();
+            assert!(matches!(parse(&args), Err(ConfigError::Conflict("--interactive", _))), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn follow_needs_files_and_prints_lines() {
+        let config = parse(&["--follow", "-n", "frog", "poem.txt"]).unwrap();
+        assert!(config.follow);
+        assert_eq!(vec!["poem.txt"], config.filenames);
+
+        assert!(matches!(parse(&["--follow", "frog"]), Err(ConfigError::Requires(..))));
+        assert!(matches!(parse(&["--follow", "frog", "-"]), Err(ConfigError::Requires(..))));
+        assert!(matches!(parse(&["--follow", "-c", "frog", "poem.txt"]), Err(ConfigError::Conflict(..))));
+        assert!(matches!(parse(&["--follow", "-m1", "frog", "poem.txt"]), Err(ConfigError::Conflict(..))));
+    }
+
+    #[test]
+    fn json_cannot_count() {
+        assert!(parse(&["--json", "frog"]).unwrap().json);
+        assert!(matches!(parse(&["--json", "-c", "frog"]), Err(ConfigError::Conflict(..))));
+        assert!(matches!(parse(&["--json", "-l", "frog"]), Err(ConfigError::Conflict(..))));
+    }
+
+    #[test]
+    fn output_modes() {
+        let config = parse(&["-lq", "--max-count=3", "frog"]).unwrap();
+
+        assert!(config.files_with_matches);
+        assert!(config.quiet);
+        assert_eq!(Some(3), config.max_count);
+        assert_eq!(None, parse(&["frog"]).unwrap().max_count);
+
+        assert!(parse(&["--files-without-match", "--silent", "frog"]).unwrap().files_without_match);
+        assert!(matches!(parse(&["-l", "-L", "frog"]), Err(ConfigError::Conflict("-l", "-L"))));
+    }
+
+    #[test]
+    fn jobs_must_be_positive() {
+        assert_eq!(3, parse(&["-j3", "frog"]).unwrap().jobs);
+        assert_eq!(1, parse(&["--jobs=1", "frog"]).unwrap().jobs);
+        assert!(parse(&["frog"]).unwrap().jobs >= 1);
+
+        assert!(matches!(parse(&["-j", "0", "frog"]), Err(ConfigError::InvalidValue(..))));
+    }
+
+    #[test]
+    fn color_takes_an_optional_value() {
+        assert_eq!(ColorChoice::Auto, parse(&["frog"]).unwrap().color);
+        assert_eq!(ColorChoice::Auto, parse(&["--color", "frog"]).unwrap().color);
+        assert_eq!(ColorChoice::Always, parse(&["--colour=always", "frog"]).unwrap().color);
+        assert_eq!(ColorChoice::Never, parse(&["--color=never", "frog"]).unwrap().color);
+
+        assert!(matches!(parse(&["--color=pink", "frog"]), Err(ConfigError::InvalidValue(..))));
+    }
+
+    #[test]
+    fn command_line_wins_over_config_file() {
+        let config = with_defaults(&["-n", "-A2", "--color=always"], &["-A1", "frog"], false).unwrap();
+
+        assert!(config.line_number);
+        assert_eq!(1, config.after_context);
+        assert_eq!(ColorChoice::Always, config.color);
+
+        let config = with_defaults(&["-n", "--color=always"], &["-N", "--color=never", "frog"], false).unwrap();
+
+        assert!(!config.line_number);
+        assert_eq!(ColorChoice::Never, config.color);
+    }
+
+    #[test]
+    fn command_line_modes_replace_the_config_files() {
+        let config = with_defaults(&["-l"], &["-L", "frog"], false).unwrap();
+        assert!(config.files_without_match && !config.files_with_matches);
+
+        let config = with_defaults(&["-c"], &["-l", "frog"], false).unwrap();
+        assert!(config.files_with_matches && !config.count);
+
+        let config = with_defaults(&["--json"], &["-c", "frog"], false).unwrap();
+        assert!(config.count && !config.json);
+
+        let config = with_defaults(&["--fuzzy", "--top=5"], &["-q", "frog"], false).unwrap();
+        assert!(config.quiet && config.fuzzy.is_none());
+
+        // the rest of the config file still counts
+        let config = with_defaults(&["-n", "-c"], &["-q", "frog"], false).unwrap();
+        assert!(config.line_number && config.quiet && !config.count);
+
+        // but the command line can't disagree with itself
+        assert!(matches!(with_defaults(&["-c"], &["-l", "-L", "frog"], false), Err(ConfigError::Conflict(..))));
+    }
+
+    #[test]
+    fn command_line_modes_drop_the_config_files_options_they_cant_use() {
+        let config = with_defaults(&["-v", "-j4", "-m3"], &["--fuzzy", "frog"], false).unwrap();
+        assert!(config.fuzzy.is_some() && !config.invert_match);
+        assert_eq!(None, config.max_count);
+
+        let config = with_defaults(&["-v", "-C2"], &["--interactive", "poem.txt"], false).unwrap();
+        assert!(config.interactive && !config.invert_match);
+        assert_eq!((0, 0), (config.before_context, config.after_context));
+
+        let config = with_defaults(&["-z", "-v"], &["--replace=toad", "--in-place", "frog", "poem.txt"], false).unwrap();
+        assert!(config.in_place && !config.invert_match && !config.decompress);
+
+        // an option on the command line drops the config file's mode too
+        let config = with_defaults(&["--fuzzy"], &["-v", "frog"], false).unwrap();
+        assert!(config.invert_match && config.fuzzy.is_none());
+
+        // options that need another are only kept if they get it
+        let config = with_defaults(&["--backup=~", "--top=5"], &["frog"], false).unwrap();
+        assert_eq!(None, config.backup);
+        assert!(matches!(with_defaults(&[], &["--top=5", "frog"], false), Err(ConfigError::Requires(..))));
+
+        // and a config file that disagrees with itself is still an error
+        assert!(matches!(with_defaults(&["--fuzzy", "-v"], &["frog"], false), Err(ConfigError::Conflict(..))));
+    }
+
+    #[test]
+    fn invert_match_can_be_undone() {
+        assert!(with_defaults(&["-v"], &["frog"], false).unwrap().invert_match);
+        assert!(!with_defaults(&["-v"], &["--no-invert-match", "frog"], false).unwrap().invert_match);
+    }
+
+    #[test]
+    fn config_file_wins_over_case_insensitive() {
+        assert!(!with_defaults(&[], &["frog"], true).unwrap().case_sensitive);
+        assert!(with_defaults(&["-s"], &["frog"], true).unwrap().case_sensitive);
+        assert!(with_defaults(&["-i"], &["-s", "frog"], false).unwrap().case_sensitive);
+        assert!(!with_defaults(&["-s"], &["--ignore-case", "frog"], false).unwrap().case_sensitive);
+        assert!(parse(&["--case-sensitive", "frog"]).unwrap().case_sensitive);
+
+        // -S is saying something about case too, wherever it comes from
+        assert!(with_defaults(&["-S"], &["Nobody"], true).unwrap().case_sensitive);
+        assert!(with_defaults(&[], &["-S", "Nobody"], true).unwrap().case_sensitive);
+        assert!(!with_defaults(&["-S"], &["nobody"], true).unwrap().case_sensitive);
+        assert!(with_defaults(&["-S"], &["-s", "nobody"], true).unwrap().case_sensitive);
+    }
+
+    #[test]
+    fn config_file_only_sets_options() {
+        assert!(matches!(with_defaults(&["frog"], &["toad"], false), Err(ConfigError::ConfigFileArg(..))));
+        assert!(matches!(with_defaults(&["-e", "frog"], &["toad"], false), Err(ConfigError::ConfigFileArg(..))));
+        assert!(matches!(with_defaults(&["--frobnicate"], &["toad"], false), Err(ConfigError::UnknownFlag(_))));
+
+        // and --no-config is only for the command line, where it's a no-op by
+        // the time we get here
+        assert!(parse(&["--no-config", "frog"]).is_ok());
+    }
+
+    #[test]
+    fn config_files_skip_comments_and_blank_lines() {
+        let path = std::env::temp_dir().join(format!("minigrep_config_{}", std::process::id()));
+        fs::write(&path, "# always number lines\n  --line-number  \n\n--context=2\n").unwrap();
+        let path = path.to_str().unwrap().to_string();
+
+        let defaults = read_defaults(path.clone()).unwrap();
+        assert_eq!(vec!["--line-number", "--context=2"], defaults.args);
+
+        let config = Config::parse(Some(defaults), strings(&["frog"]), false).unwrap();
+        assert!(config.line_number);
+        assert_eq!(2, config.before_context);
+
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(read_defaults(path), Err(ConfigError::ConfigFile(..))));
+    }
+
+    #[test]
+    fn help_version_and_unknown_flags() {
+        assert!(matches!(parse(&["--help"]), Err(ConfigError::Help)));
+        assert!(matches!(parse(&["-V"]), Err(ConfigError::Version)));
+        assert!(matches!(parse(&["--frobnicate", "x", "y"]), Err(ConfigError::UnknownFlag(_))));
+        assert!(matches!(parse(&[]), Err(ConfigError::MissingQuery)));
+    }
+}
@@ -0,0 +1,181 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+
+use flate2::bufread::MultiGzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+// The compressed formats we can search inside of
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Gzip,
+    Zstd,
+}
+
+impl Format {
+    // Recognise a format by the magic bytes it starts with, rather than by
+    // the file name, so rotated logs like syslog.2 work too
+    pub fn detect(start: &[u8]) -> Option<Format> {
+        if start.starts_with(&[0x1f, 0x8b]) {
+            Some(Format::Gzip)
+        } else if start.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Format::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+// Wrap `reader` so that reading it gives the decompressed contents, if it
+// starts like something compressed. Otherwise it's handed back as it is,
+// unless `force` is set, in which case that's an error.
+//
+// Decompression happens as the lines are read, so a big log never has to fit
+// in memory (or on disk) uncompressed.
+pub fn reader<R: BufRead + 'static>(mut reader: R, force: bool) -> io::Result<Box<dyn BufRead>> {
+    let format = Format::detect(reader.fill_buf()?);
+
+    match format {
+        // gzip files can be several compressed files one after the other,
+        // like `cat a.gz b.gz` gives, and zcat reads all of them
+        Some(Format::Gzip) => Ok(Box::new(BufReader::new(Decoded(MultiGzDecoder::new(Source(reader)))))),
+        Some(Format::Zstd) => Ok(Box::new(BufReader::new(Decoded(ZstdDecoder::with_buffer(Source(reader))?)))),
+        None if force => Err(io::Error::new(io::ErrorKind::InvalidData, "not in gzip or zstd format")),
+        None => Ok(Box::new(reader)),
+    }
+}
+
+// The compressed file being read by a decoder, whose errors are marked as
+// being about getting at the file rather than what's in it
+struct Source<R>(R);
+
+#[derive(Debug)]
+struct SourceError(io::Error);
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for SourceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+fn from_source(e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), SourceError(e))
+}
+
+impl<R: Read> Read for Source<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(from_source)
+    }
+}
+
+impl<R: BufRead> BufRead for Source<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.0.fill_buf().map_err(from_source)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.0.consume(amt)
+    }
+}
+
+// A decoder, which says what went wrong however it likes (a bad gzip header
+// is InvalidInput to flate2, for one). Anything but trouble reading the
+// file underneath is made InvalidData, so it's reported as the file being
+// corrupt.
+struct Decoded<D>(D);
+
+impl<D: Read> Read for Decoded<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(|e| {
+            let kind = e.kind();
+
+            if kind == io::ErrorKind::Interrupted {
+                return e;
+            }
+
+            match e.into_inner() {
+                Some(inner) => match inner.downcast::<SourceError>() {
+                    Ok(source) => source.0,
+                    Err(inner) => io::Error::new(io::ErrorKind::InvalidData, inner),
+                },
+                None => io::Error::new(io::ErrorKind::InvalidData, format!("corrupt compressed data: {}", kind)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    fn decompressed(contents: Vec<u8>, force: bool) -> io::Result<String> {
+        let mut text = String::new();
+        reader(io::Cursor::new(contents), force)?.read_to_string(&mut text)?;
+        Ok(text)
+    }
+
+    fn gzip(text: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn formats_are_recognised_by_their_magic_bytes() {
+        let mut concatenated = gzip("I'm nobody!\n");
+        concatenated.extend(gzip("Who are you?\n"));
+        assert_eq!("I'm nobody!\nWho are you?\n", decompressed(concatenated, false).unwrap());
+
+        let zstd = zstd::encode_all(&b"How public, like a frog\n"[..], 0).unwrap();
+        assert_eq!(Some(Format::Zstd), Format::detect(&zstd));
+        assert_eq!("How public, like a frog\n", decompressed(zstd, false).unwrap());
+    }
+
+    // Reads like the start of a gzip file, then fails
+    struct Unreadable(bool);
+
+    impl Read for Unreadable {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if std::mem::replace(&mut self.0, true) {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "can't read that"));
+            }
+
+            buf[..2].copy_from_slice(&[0x1f, 0x8b]);
+            Ok(2)
+        }
+    }
+
+    #[test]
+    fn corrupt_data_is_invalid_data() {
+        let mut gzip = gzip("I'm nobody!\n");
+        gzip[3] = 0xff;
+        assert_eq!(io::ErrorKind::InvalidData, decompressed(gzip, false).unwrap_err().kind());
+        assert_eq!(io::ErrorKind::InvalidData, decompressed(vec![0x1f, 0x8b, 0, 0], false).unwrap_err().kind());
+
+        let mut zstd = zstd::encode_all(&b"How public, like a frog\n"[..], 0).unwrap();
+        zstd[4] = 0xff;
+        assert_eq!(io::ErrorKind::InvalidData, decompressed(zstd, false).unwrap_err().kind());
+
+        // but not being able to read the file at all is still just that
+        let mut text = String::new();
+        let error = reader(BufReader::new(Unreadable(false)), false).unwrap().read_to_string(&mut text).unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, error.kind());
+        assert_eq!("can't read that", error.to_string());
+    }
+
+    #[test]
+    fn plain_text_is_left_alone_unless_forced() {
+        assert_eq!("plain text\n", decompressed(b"plain text\n".to_vec(), false).unwrap());
+        assert_eq!(io::ErrorKind::InvalidData, decompressed(b"plain text\n".to_vec(), true).unwrap_err().kind());
+    }
+}
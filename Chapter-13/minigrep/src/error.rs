@@ -0,0 +1,145 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{BuildError, ConfigError};
+
+// Anything that can stop minigrep, sorted by what the person running it
+// would have to do about it. Each kind gets its own exit code.
+#[derive(Debug)]
+pub enum MinigrepError {
+    // the arguments (or the config file's) don't make sense
+    Usage(ConfigError),
+    InvalidPattern(BuildError),
+    // reading or writing something failed. The path is None for stdin and
+    // stdout, or when there's no one file to blame.
+    Io(Option<PathBuf>, io::Error),
+    // something read fine but wasn't what we expected: a pattern file
+    // that isn't UTF-8, or a file that isn't compressed the way it claims
+    Encoding(Option<PathBuf>, io::Error),
+}
+
+impl MinigrepError {
+    // An I/O error from working with `path`, which is an encoding error if
+    // the data was the problem rather than getting at it
+    pub fn io(path: Option<&Path>, e: io::Error) -> MinigrepError {
+        let path = path.map(Path::to_path_buf);
+
+        if e.kind() == io::ErrorKind::InvalidData {
+            MinigrepError::Encoding(path, e)
+        } else {
+            MinigrepError::Io(path, e)
+        }
+    }
+
+    // What minigrep exits with. 0 and 1 are taken by whether anything was
+    // found, so like grep every error is 2 or more.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MinigrepError::Usage(_) => 2,
+            MinigrepError::InvalidPattern(_) => 3,
+            MinigrepError::Io(..) => 4,
+            MinigrepError::Encoding(..) => 5,
+        }
+    }
+}
+
+impl From<ConfigError> for MinigrepError {
+    fn from(e: ConfigError) -> MinigrepError {
+        match e {
+            ConfigError::InvalidPattern(e) => MinigrepError::InvalidPattern(BuildError::Regex(e)),
+            ConfigError::PatternFile(path, e) | ConfigError::ConfigFile(path, e) => {
+                MinigrepError::io(Some(Path::new(&path)), e)
+            },
+            e => MinigrepError::Usage(e),
+        }
+    }
+}
+
+// For errors there's no one file to blame for, like failing to write to
+// stdout
+impl From<io::Error> for MinigrepError {
+    fn from(e: io::Error) -> MinigrepError {
+        MinigrepError::io(None, e)
+    }
+}
+
+impl From<BuildError> for MinigrepError {
+    fn from(e: BuildError) -> MinigrepError {
+        MinigrepError::InvalidPattern(e)
+    }
+}
+
+impl fmt::Display for MinigrepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MinigrepError::Usage(e) => write!(f, "{}", e),
+            MinigrepError::InvalidPattern(e) => write!(f, "{}", e),
+            // grep style, e.g. "poem.txt: No such file or directory"
+            MinigrepError::Io(Some(path), e) | MinigrepError::Encoding(Some(path), e) => {
+                write!(f, "{}: {}", path.display(), e)
+            },
+            MinigrepError::Io(None, e) | MinigrepError::Encoding(None, e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for MinigrepError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MinigrepError::Usage(e) => Some(e),
+            MinigrepError::InvalidPattern(e) => Some(e),
+            MinigrepError::Io(_, e) | MinigrepError::Encoding(_, e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    fn parse(args: &[&str]) -> MinigrepError {
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+
+        Config::parse(None, args, false).err().unwrap().into()
+    }
+
+    #[test]
+    fn config_errors_are_sorted_by_kind() {
+        assert_eq!(2, parse(&["--frobnicate", "frog"]).exit_code());
+        assert_eq!(3, parse(&["-E", "fr(og"]).exit_code());
+
+        let missing = parse(&["-f", "no/such/queries.txt"]);
+        assert_eq!(4, missing.exit_code());
+        assert!(missing.to_string().starts_with("no/such/queries.txt: "), "{}", missing);
+    }
+
+    #[test]
+    fn bad_data_is_an_encoding_error() {
+        let path = std::env::temp_dir().join(format!("minigrep_latin1_{}", std::process::id()));
+        std::fs::write(&path, b"caf\xe9\n").unwrap();
+
+        let error = parse(&["-f", path.to_str().unwrap()]);
+        assert!(matches!(error, MinigrepError::Encoding(Some(_), _)));
+        assert_eq!(5, error.exit_code());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_compressed_files_are_encoding_errors() {
+        let path = std::env::temp_dir().join(format!("minigrep_corrupt_{}.gz", std::process::id()));
+        std::fs::write(&path, [0x1f, 0x8b, 0xff, 0xff, 0xff, 0xff]).unwrap();
+
+        let args = vec!["frog".to_string(), path.to_str().unwrap().to_string()];
+        let config = Config::parse(None, args, false).unwrap();
+
+        let error = crate::run(config).unwrap_err();
+        assert!(matches!(error, MinigrepError::Encoding(Some(_), _)), "{:?}", error);
+        assert_eq!(5, error.exit_code());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use same_file::Handle;
+
+use crate::output::Printer;
+use crate::walk::Input;
+use crate::{Config, Line, Matcher, MinigrepError};
+
+// How long to wait between looking for new lines. Checking is only a read
+// and a stat per file, so this is about how quickly lines show up.
+const INTERVAL: Duration = Duration::from_millis(250);
+
+// A file we're reading lines from as they're added to it, like `tail -f`.
+// It keeps following the path rather than the file: if the file is truncated
+// or another one takes its place, it starts over from the top.
+pub struct Tail {
+    path: PathBuf,
+    // the open file, which can also tell us whether `path` still names it
+    handle: Handle,
+    // how much of the file we've read
+    read: u64,
+    // the number and offset of the line we're in the middle of
+    number: usize,
+    start: u64,
+    // what we've read of that line so far
+    partial: Vec<u8>,
+}
+
+impl Tail {
+    // Start following the file at `path` from its end. The lines already
+    // there are counted (but not read into memory), so new ones get the right
+    // numbers.
+    pub fn open(path: &Path) -> io::Result<Tail> {
+        let mut tail = Tail {
+            path: path.to_path_buf(),
+            handle: Handle::from_file(File::open(path)?)?,
+            read: 0,
+            number: 0,
+            start: 0,
+            partial: Vec::new(),
+        };
+
+        tail.read(&mut |_, _, _| Ok(()))?;
+
+        Ok(tail)
+    }
+
+    // Read whatever whole lines have been added since last time, calling
+    // `each` with each one's number, offset, bytes and text, without its
+    // line ending. A line still being written is held back until it's finished,
+    // unless the file is replaced or truncated first.
+    pub fn poll<F>(&mut self, mut each: F) -> io::Result<()>
+    where
+        F: FnMut(usize, u64, &[u8], &str) -> io::Result<()>,
+    {
+        let mut each = |number, offset, line: &[u8]| each(number, offset, line, &String::from_utf8_lossy(line));
+
+        // anything written to the old file before it was replaced still
+        // counts
+        self.read(&mut each)?;
+
+        // until a rotated file's replacement turns up, there's nothing new
+        // to read anyway
+        let replaced = match Handle::from_path(&self.path) {
+            Ok(handle) if handle != self.handle => Some(handle),
+            Ok(_) => None,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        if let Some(handle) = replaced {
+            self.start_over(&mut each)?;
+            self.handle = handle;
+        } else if self.handle.as_file().metadata()?.len() < self.read {
+            // truncation can only be told apart from new lines if the file
+            // is smaller than it was, so truncating a file and then writing
+            // more to it than there was before looks like appending
+            self.start_over(&mut each)?;
+            self.handle.as_file_mut().seek(SeekFrom::Start(0))?;
+        } else {
+            return Ok(());
+        }
+
+        self.read(&mut each)
+    }
+
+    // Finish off the line we were in the middle of, and get ready to read a
+    // file from the top
+    fn start_over<F>(&mut self, each: &mut F) -> io::Result<()>
+    where
+        F: FnMut(usize, u64, &[u8]) -> io::Result<()>,
+    {
+        if !self.partial.is_empty() {
+            each(self.number + 1, self.start, trim_cr(&self.partial))?;
+        }
+
+        self.read = 0;
+        self.number = 0;
+        self.start = 0;
+        self.partial.clear();
+
+        Ok(())
+    }
+
+    // Read to the end of the file, calling `each` for every line finished
+    // along the way
+    fn read<F>(&mut self, each: &mut F) -> io::Result<()>
+    where
+        F: FnMut(usize, u64, &[u8]) -> io::Result<()>,
+    {
+        let mut chunk = [0; 64 * 1024];
+
+        loop {
+            let len = self.handle.as_file_mut().read(&mut chunk)?;
+
+            if len == 0 {
+                return Ok(());
+            }
+
+            self.read += len as u64;
+            self.partial.extend_from_slice(&chunk[..len]);
+
+            let mut used = 0;
+
+            for end in memchr::memchr_iter(b'\n', &self.partial) {
+                self.number += 1;
+                each(self.number, self.start, trim_cr(&self.partial[used..end]))?;
+
+                self.start += (end + 1 - used) as u64;
+                used = end + 1;
+            }
+
+            self.partial.drain(..used);
+        }
+    }
+}
+
+// Same line endings as str::lines
+fn trim_cr(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+// Follow every input, printing the lines `matcher` selects as they're added,
+// until we're interrupted or something goes wrong. Lines are printed under
+// the name of the file they came from, so context from one file isn't
+// carried over once another has printed something.
+pub fn run<W>(config: &Config, matcher: &Matcher, inputs: &[Input], printer: &mut Printer<W>) -> Result<(), MinigrepError>
+where
+    W: Write,
+{
+    let mut tails = Vec::new();
+
+    for input in inputs {
+        tails.push(Tail::open(&input.path).map_err(|e| crate::blame(input, e))?);
+    }
+
+    // which of the inputs we printed lines from last
+    let mut current = None;
+    let mut submatches = Vec::new();
+
+    loop {
+        for (i, tail) in tails.iter_mut().enumerate() {
+            tail.poll(|number, offset, bytes, text| {
+                if current != Some(i) {
+                    printer.start_file(crate::display_name(&inputs[i]))?;
+                    current = Some(i);
+                }
+
+                let is_match = matcher.is_match(text);
+
+                submatches.clear();
+                if is_match {
+                    matcher.find_all(text, &mut submatches);
+                }
+
+                crate::print_line(config, matcher, printer, &Line {
+                    number,
+                    offset,
+                    text,
+                    bytes,
+                    matches: &submatches,
+                    matched: is_match != config.invert_match,
+                })
+            }).map_err(|e| crate::blame(&inputs[i], e))?;
+        }
+
+        printer.flush()?;
+        thread::sleep(INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+
+    // Everything `tail` has to say, as (number, offset, text)
+    fn poll(tail: &mut Tail) -> Vec<(usize, u64, String)> {
+        let mut lines = Vec::new();
+
+        tail.poll(|number, offset, _, text| {
+            lines.push((number, offset, text.to_string()));
+            Ok(())
+        }).unwrap();
+
+        lines
+    }
+
+    fn append(path: &Path, contents: &str) {
+        OpenOptions::new().append(true).open(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    fn lines(expected: &[(usize, u64, &str)]) -> Vec<(usize, u64, String)> {
+        expected.iter().map(|&(number, offset, text)| (number, offset, text.to_string())).collect()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("minigrep_follow_{}_{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn only_new_whole_lines_are_read() {
+        let path = temp_path("append");
+        fs::write(&path, "old\nlines\n").unwrap();
+
+        let mut tail = Tail::open(&path).unwrap();
+        assert!(poll(&mut tail).is_empty());
+
+        append(&path, "a new line\r\nand half");
+        assert_eq!(lines(&[(3, 10, "a new line")]), poll(&mut tail));
+
+        append(&path, " of one\n");
+        assert_eq!(lines(&[(4, 22, "and half of one")]), poll(&mut tail));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated_files_start_over() {
+        let path = temp_path("truncate");
+        fs::write(&path, "old\nlines\n").unwrap();
+
+        let mut tail = Tail::open(&path).unwrap();
+
+        fs::write(&path, "new\n").unwrap();
+        assert_eq!(lines(&[(1, 0, "new")]), poll(&mut tail));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rotated_files_are_finished_then_replaced() {
+        let path = temp_path("rotate");
+        let rotated = temp_path("rotate.1");
+        fs::write(&path, "old\n").unwrap();
+
+        let mut tail = Tail::open(&path).unwrap();
+
+        append(&path, "last words\nunfinished");
+        fs::rename(&path, &rotated).unwrap();
+
+        // nothing's replaced it yet
+        assert_eq!(lines(&[(2, 4, "last words")]), poll(&mut tail));
+
+        fs::write(&path, "first\n").unwrap();
+        assert_eq!(lines(&[(3, 15, "unfinished"), (1, 0, "first")]), poll(&mut tail));
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+}
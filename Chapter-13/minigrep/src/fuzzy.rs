@@ -0,0 +1,254 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+// Points for each query character we find in a line, with bonuses for runs
+// of characters and for characters that start a word, so "mg" likes
+// "minigrep" less than "magic" and "my grep" more than both.
+const CHAR_SCORE: u32 = 10;
+const CONSECUTIVE_BONUS: u32 = 5;
+const WORD_START_BONUS: u32 = 5;
+
+// Score how well `line` matches `query`, ignoring case, or None if it
+// doesn't. Higher is better.
+//
+// A line matches if the query's characters appear in it in order, like
+// "lvlng" in "livelong", or failing that if some part of it is only a typo or
+// two away from the query, like "admirng" in "admiring". Typos are allowed
+// one for every four characters of the query, and score less than any
+// in-order match of the same query: a point for each character that isn't
+// a typo, where an in-order match gets CHAR_SCORE for its first character
+// and at least a point for each of the rest.
+pub fn score(query: &str, line: &str) -> Option<u32> {
+    let query: Vec<char> = query.chars().flat_map(char::to_lowercase).collect();
+    let line: Vec<char> = line.chars().flat_map(char::to_lowercase).collect();
+
+    if query.is_empty() {
+        return Some(0);
+    }
+
+    if let Some(score) = subsequence(&query, &line) {
+        return Some(score);
+    }
+
+    let typos = typos(&query, &line);
+
+    if typos <= query.len() / 4 {
+        Some((query.len() - typos) as u32)
+    } else {
+        None
+    }
+}
+
+// The best score for `query` as a subsequence of `line`. Each place the first
+// character appears is tried as a start, taking every later character as
+// early as possible from there.
+fn subsequence(query: &[char], line: &[char]) -> Option<u32> {
+    let mut best = None;
+
+    for start in 0..line.len() {
+        if line[start] != query[0] {
+            continue;
+        }
+
+        let mut score = 0;
+        let mut previous: Option<usize> = None;
+        let mut at = start;
+
+        for &wanted in query {
+            let found = match line[at..].iter().position(|&c| c == wanted) {
+                Some(found) => at + found,
+                None => return best,
+            };
+
+            score += CHAR_SCORE;
+
+            if found == 0 || !line[found - 1].is_alphanumeric() {
+                score += WORD_START_BONUS;
+            }
+
+            match previous {
+                Some(previous) if found == previous + 1 => score += CONSECUTIVE_BONUS,
+                // a point off for every character we had to skip
+                Some(previous) => score -= ((found - previous - 1) as u32).min(CHAR_SCORE - 1),
+                None => (),
+            }
+
+            previous = Some(found);
+            at = found + 1;
+        }
+
+        best = best.max(Some(score));
+    }
+
+    best
+}
+
+// The fewest edits (insertions, deletions or substitutions) that turn
+// `query` into some part of `line`, by the usual edit distance table except
+// that a match can start anywhere in the line for free.
+fn typos(query: &[char], line: &[char]) -> usize {
+    // row[j] is the distance between the query so far and the best part of
+    // the line ending at character j
+    let mut row = vec![0; line.len() + 1];
+
+    for (i, &q) in query.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, &c) in line.iter().enumerate() {
+            let substitute = diagonal + if q == c { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row.into_iter().min().unwrap_or(query.len())
+}
+
+// Keeps the `limit` best-scoring things offered to it, preferring earlier
+// ones when scores tie, without holding on to anything else.
+pub struct Top<T> {
+    limit: usize,
+    // the worst of the best so far is on top, ready to be pushed out
+    heap: BinaryHeap<Reverse<Ranked<T>>>,
+    offered: usize,
+}
+
+struct Ranked<T> {
+    score: u32,
+    order: usize,
+    item: T,
+}
+
+// Higher scores rank higher, then earlier offers. `item` doesn't count.
+impl<T> Ord for Ranked<T> {
+    fn cmp(&self, other: &Ranked<T>) -> Ordering {
+        self.score.cmp(&other.score).then(other.order.cmp(&self.order))
+    }
+}
+
+impl<T> PartialOrd for Ranked<T> {
+    fn partial_cmp(&self, other: &Ranked<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> PartialEq for Ranked<T> {
+    fn eq(&self, other: &Ranked<T>) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Ranked<T> {}
+
+impl<T> Top<T> {
+    pub fn new(limit: usize) -> Top<T> {
+        Top { limit, heap: BinaryHeap::new(), offered: 0 }
+    }
+
+    // Consider something with `score`. `item` is only called if it makes the
+    // cut, so it can be where the copying happens.
+    pub fn offer<F: FnOnce() -> T>(&mut self, score: u32, item: F) {
+        let order = self.offered;
+        self.offered += 1;
+
+        if self.heap.len() == self.limit {
+            // anything offered now loses a tie, so it has to do better
+            match self.heap.peek() {
+                Some(Reverse(worst)) if score > worst.score => {
+                    self.heap.pop();
+                },
+                _ => return,
+            }
+        }
+
+        self.heap.push(Reverse(Ranked { score, order, item: item() }));
+    }
+
+    // Everything kept, best first
+    pub fn into_sorted(self) -> Vec<(u32, T)> {
+        // sorting the Reverses ascending puts the best first
+        self.heap.into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(ranked)| (ranked.score, ranked.item))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subsequences_match_in_order() {
+        assert!(score("lvlng", "To tell your name the livelong day").is_some());
+        assert!(score("gnolevil", "To tell your name the livelong day").is_none());
+        assert!(score("FROG", "How public, like a frog").is_some());
+    }
+
+    #[test]
+    fn runs_and_word_starts_score_higher() {
+        let query = "mg";
+
+        let word_starts = score(query, "my grep").unwrap();
+        let run = score(query, "magic").unwrap();
+        let scattered = score(query, "minigrep").unwrap();
+
+        assert!(word_starts > run, "{} > {}", word_starts, run);
+        assert!(run > scattered, "{} > {}", run, scattered);
+        assert!(score("frog", "a frog").unwrap() > score("frog", "afrog").unwrap());
+    }
+
+    #[test]
+    fn typos_are_tolerated_a_little() {
+        // "admirnig" isn't a subsequence of "admiring", but it's one swap off
+        assert_eq!(2, typos(&chars("admirnig"), &chars("to an admiring bog")));
+        assert!(score("admirnig", "To an admiring bog!").is_some());
+
+        // one typo is too many for a short query
+        assert!(score("bgo", "To an admiring bog!").is_none());
+
+        // and typos rank below the real thing
+        assert!(score("admiring", "admiring").unwrap() > score("admirnig", "admiring").unwrap());
+    }
+
+    #[test]
+    fn typos_rank_below_every_in_order_match() {
+        let query = "abcdefgh";
+
+        // as spread out as an in-order match gets, with every skip costing
+        // as much as it can
+        let scattered = "azzzzzzzzzzbzzzzzzzzzzczzzzzzzzzzzdzzzzzzzzzzezzzzzzzzzzfzzzzzzzzzzgzzzzzzzzzzh";
+        let one_typo = "abcdxfgh";
+        let two_typos = "abxdxfgh";
+
+        let mut top = Top::new(3);
+        for line in &[two_typos, one_typo, scattered] {
+            top.offer(score(query, line).unwrap(), || *line);
+        }
+
+        let ranked: Vec<_> = top.into_sorted().into_iter().map(|(_, line)| line).collect();
+        assert_eq!(vec![scattered, one_typo, two_typos], ranked);
+    }
+
+    #[test]
+    fn top_keeps_the_best_and_the_earliest() {
+        let mut top = Top::new(3);
+        let mut made = 0;
+
+        for (score, name) in &[(5, "a"), (9, "b"), (5, "c"), (7, "d"), (1, "e"), (9, "f")] {
+            top.offer(*score, || {
+                made += 1;
+                *name
+            });
+        }
+
+        assert_eq!(vec![(9, "b"), (9, "f"), (7, "d")], top.into_sorted());
+        // "e" never made the cut, so it was never made
+        assert_eq!(5, made);
+    }
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+}
@@ -0,0 +1,528 @@
+use std::io::{self, IsTerminal, Read, Write};
+use std::ops::Range;
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Color, Print, SetAttribute, SetForegroundColor};
+use crossterm::terminal::{self, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{cursor, execute, queue};
+
+use crate::output::Printer;
+use crate::walk::Input;
+use crate::{Config, ConfigError, Line, Matcher, MinigrepError, Searcher};
+
+// A line that matches the query as it stands
+struct Hit {
+    // index into `App::files`
+    file: usize,
+    number: usize,
+    text: String,
+    matches: Vec<Range<usize>>,
+}
+
+// What the top line of the screen is asking for
+#[derive(Debug, PartialEq)]
+enum Prompt {
+    Query,
+    // a line number to jump to, as typed so far
+    GoTo(String),
+}
+
+// What to do after a key press
+#[derive(Debug, PartialEq)]
+enum Action {
+    Continue,
+    Quit,
+    // quit, and print the selected line like a normal search would
+    Print,
+}
+
+// Everything the interactive view shows, kept apart from the terminal so it
+// can be tested without one
+struct App {
+    // every file's name and contents, read once up front
+    files: Vec<(String, Vec<u8>)>,
+    show_filename: bool,
+    query: String,
+    regex: bool,
+    word_regexp: bool,
+    ignore_case: bool,
+    hits: Vec<Hit>,
+    // why the query doesn't work, if it doesn't
+    error: Option<String>,
+    // which hit is highlighted, and which one is at the top of the screen
+    selected: usize,
+    scroll: usize,
+    prompt: Prompt,
+}
+
+impl App {
+    fn new(config: &Config, files: Vec<(String, Vec<u8>)>) -> App {
+        let mut app = App {
+            show_filename: config.recursive || files.len() > 1,
+            files,
+            query: config.patterns.first().cloned().unwrap_or_default(),
+            regex: config.regex_syntax,
+            word_regexp: config.word_regexp,
+            ignore_case: !config.case_sensitive,
+            hits: Vec::new(),
+            error: None,
+            selected: 0,
+            scroll: 0,
+            prompt: Prompt::Query,
+        };
+
+        app.search();
+        app
+    }
+
+    // Search every file again for the query as it stands. If it isn't a
+    // valid regex (yet), the last good results stay up.
+    fn search(&mut self) {
+        let mut pattern = if self.regex {
+            self.query.clone()
+        } else {
+            regex::escape(&self.query)
+        };
+
+        if self.word_regexp {
+            pattern = format!(r"\b(?:{})\b", pattern);
+        }
+
+        if self.ignore_case {
+            pattern = format!("(?i){}", pattern);
+        }
+
+        let matcher = match Matcher::regex(&[pattern]) {
+            Ok(matcher) => matcher,
+            Err(e) => {
+                self.error = Some(e.to_string());
+                return;
+            },
+        };
+
+        let mut hits = Vec::new();
+
+        for (file, (_, contents)) in self.files.iter().enumerate() {
+            // neither searching memory nor collecting hits can fail
+            let _ = Searcher::new().search_slice(&matcher, contents, &mut |line: &Line| {
+                hits.push(Hit {
+                    file,
+                    number: line.number,
+                    text: line.text.to_string(),
+                    matches: line.matches.iter().map(|submatch| submatch.range.clone()).collect(),
+                });
+                Ok(true)
+            });
+        }
+
+        self.hits = hits;
+        self.error = None;
+        self.selected = 0;
+    }
+
+    // Handle a key press, given how many hits fit on a page
+    fn key(&mut self, key: KeyEvent, page: usize) -> Action {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+
+        match (key.code, &mut self.prompt) {
+            (KeyCode::Char('c'), _) if control => return Action::Quit,
+            (KeyCode::Esc, Prompt::Query) => return Action::Quit,
+            (KeyCode::Esc, Prompt::GoTo(_)) => self.prompt = Prompt::Query,
+
+            (KeyCode::Char(c), Prompt::GoTo(number)) if c.is_ascii_digit() => number.push(c),
+            (KeyCode::Backspace, Prompt::GoTo(number)) => {
+                number.pop();
+            },
+            (KeyCode::Enter, Prompt::GoTo(number)) => {
+                if let Ok(number) = number.parse() {
+                    self.go_to(number);
+                }
+                self.prompt = Prompt::Query;
+            },
+
+            (KeyCode::Enter, Prompt::Query) if !self.hits.is_empty() => return Action::Print,
+            (KeyCode::Char('g'), _) if control => self.prompt = Prompt::GoTo(String::new()),
+            (KeyCode::Char('t'), _) if control => {
+                self.ignore_case = !self.ignore_case;
+                self.search();
+            },
+            (KeyCode::Char(c), Prompt::Query) if !control => {
+                self.query.push(c);
+                self.search();
+            },
+            (KeyCode::Backspace, Prompt::Query) if !self.query.is_empty() => {
+                self.query.pop();
+                self.search();
+            },
+
+            (KeyCode::Up, _) => self.select(self.selected.saturating_sub(1)),
+            (KeyCode::Down, _) => self.select(self.selected + 1),
+            (KeyCode::PageUp, _) => self.select(self.selected.saturating_sub(page)),
+            (KeyCode::PageDown, _) => self.select(self.selected + page),
+            (KeyCode::Home, _) => self.select(0),
+            (KeyCode::End, _) => self.select(self.hits.len()),
+            _ => (),
+        }
+
+        Action::Continue
+    }
+
+    fn select(&mut self, hit: usize) {
+        self.selected = hit.min(self.hits.len().saturating_sub(1));
+    }
+
+    // Select the first hit at or after line `number` of the file we're in,
+    // or the last hit in it if there's none that far down
+    fn go_to(&mut self, number: usize) {
+        let file = match self.hits.get(self.selected) {
+            Some(hit) => hit.file,
+            None => return,
+        };
+
+        let found = self.hits.iter()
+            .position(|hit| hit.file == file && hit.number >= number)
+            .or_else(|| self.hits.iter().rposition(|hit| hit.file == file));
+
+        if let Some(found) = found {
+            self.selected = found;
+        }
+    }
+
+    // Scroll as little as we can to keep the selected hit on screen
+    fn scroll_to_selected(&mut self, rows: usize) {
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if rows > 0 && self.selected >= self.scroll + rows {
+            self.scroll = self.selected + 1 - rows;
+        }
+    }
+
+    // Draw the whole screen: the prompt on the top line, the status on the
+    // bottom one, and as many hits as fit in between
+    fn render<W: Write>(&mut self, out: &mut W, width: usize, height: usize) -> io::Result<()> {
+        let rows = height.saturating_sub(2);
+        self.scroll_to_selected(rows);
+
+        queue!(out, terminal::Clear(ClearType::All))?;
+
+        for (row, hit) in self.hits.iter().enumerate().skip(self.scroll).take(rows) {
+            queue!(out, cursor::MoveTo(0, (row - self.scroll + 1) as u16))?;
+
+            if row == self.selected {
+                queue!(out, SetAttribute(Attribute::Reverse))?;
+            }
+
+            let mut prefix = format!("{}:", hit.number);
+            if self.show_filename {
+                prefix = format!("{}:{}", self.files[hit.file].0, prefix);
+            }
+
+            let mut left = width;
+            queue!(
+                out,
+                SetForegroundColor(Color::Green),
+                Print(clip(&prefix, &mut left)),
+                SetForegroundColor(Color::Reset),
+            )?;
+
+            let mut end = 0;
+            for span in &hit.matches {
+                queue!(out, Print(clip(&hit.text[end..span.start], &mut left)))?;
+                queue!(
+                    out,
+                    SetForegroundColor(Color::Red),
+                    SetAttribute(Attribute::Bold),
+                    Print(clip(&hit.text[span.clone()], &mut left)),
+                    SetAttribute(Attribute::NormalIntensity),
+                    SetForegroundColor(Color::Reset),
+                )?;
+                end = span.end;
+            }
+            queue!(out, Print(clip(&hit.text[end..], &mut left)))?;
+
+            queue!(out, SetAttribute(Attribute::Reset))?;
+        }
+
+        let status = match &self.error {
+            Some(error) => error.clone(),
+            None => format!(
+                "{} matching lines, {}  (^T case, ^G go to line, Enter print, Esc quit)",
+                self.hits.len(),
+                if self.ignore_case { "ignoring case" } else { "matching case" },
+            ),
+        };
+        let mut left = width;
+        queue!(out, cursor::MoveTo(0, height.saturating_sub(1) as u16), Print(clip(&status, &mut left)))?;
+
+        let prompt = match &self.prompt {
+            Prompt::Query => format!("> {}", self.query),
+            Prompt::GoTo(number) => format!("Go to line: {}", number),
+        };
+        let mut left = width;
+        let prompt = clip(&prompt, &mut left);
+        queue!(out, cursor::MoveTo(0, 0), Print(&prompt))?;
+
+        out.flush()
+    }
+}
+
+// As much of `text` as fits in the `left` columns we have, taking what it
+// uses out of them. Tabs and other control characters become spaces, so they
+// can't move the cursor around.
+fn clip(text: &str, left: &mut usize) -> String {
+    let clipped: String = text.chars()
+        .take(*left)
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+
+    *left -= clipped.chars().count();
+    clipped
+}
+
+// Raw mode on the alternate screen for as long as this is around, so the
+// terminal is put back however we leave
+struct Screen;
+
+impl Screen {
+    fn enter() -> io::Result<Screen> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen)?;
+        Ok(Screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+// Without a terminal to draw on and read keys from, asking for the
+// interactive view was a mistake on the command line
+fn check_terminal(stdin: &impl IsTerminal, stdout: &impl IsTerminal) -> Result<(), MinigrepError> {
+    if !stdin.is_terminal() || !stdout.is_terminal() {
+        return Err(MinigrepError::Usage(ConfigError::Requires("--interactive", "a terminal")));
+    }
+
+    Ok(())
+}
+
+// Run the interactive view over `inputs` until it's quit. Returns whether a
+// line was picked (and printed).
+pub fn run(config: &Config, inputs: &[Input]) -> Result<bool, MinigrepError> {
+    check_terminal(&io::stdin(), &io::stdout())?;
+
+    let mut files = Vec::new();
+    let mut failed = None;
+
+    for input in inputs {
+        let read = crate::open(input, config.decompress).and_then(|reader| match reader {
+            Some(mut reader) => {
+                let mut contents = Vec::new();
+                reader.read_to_end(&mut contents)?;
+                Ok(Some(contents))
+            },
+            None => Ok(None),
+        });
+
+        match read {
+            Ok(Some(contents)) => files.push((crate::display_name(input), contents)),
+            Ok(None) => (),
+            Err(e) => crate::keep_going(&mut failed, crate::blame(input, e))?,
+        }
+    }
+
+    let mut app = App::new(config, files);
+    let mut stdout = io::stdout();
+
+    let action = {
+        let _screen = Screen::enter()?;
+
+        loop {
+            let (width, height) = terminal::size()?;
+            let (width, height) = (width as usize, height as usize);
+            app.render(&mut stdout, width, height)?;
+
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+
+                match app.key(key, height.saturating_sub(2).max(1)) {
+                    Action::Continue => (),
+                    action => break action,
+                }
+            }
+        }
+    };
+
+    // a file that couldn't be read still counts against the exit code,
+    // like it would for a search
+    if action != Action::Print {
+        return failed.map_or(Ok(false), Err);
+    }
+
+    // print it the way a search would, with its line number, ready for
+    // something like `vim +N`
+    let hit = &app.hits[app.selected];
+    let (path, _) = &app.files[hit.file];
+    let matches: Vec<_> = hit.matches.iter()
+        .map(|range| crate::Submatch { range: range.clone(), pattern: 0 })
+        .collect();
+
+    let mut printer = Printer::new(stdout.lock(), true, 0, 0).show_filename(app.show_filename);
+    printer.start_file(path.clone())?;
+    printer.line(&Line {
+        number: hit.number,
+        offset: 0,
+        text: &hit.text,
+        bytes: hit.text.as_bytes(),
+        matches: &matches,
+        matched: true,
+    })?;
+
+    failed.map_or(Ok(true), Err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    fn app(args: &[&str]) -> App {
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+        let config = Config::parse(None, args, false).unwrap();
+        let poem = std::fs::read("poem.txt").unwrap();
+
+        App::new(&config, vec![("poem.txt".to_string(), poem)])
+    }
+
+    fn press(app: &mut App, keys: &[KeyCode]) -> Action {
+        let mut action = Action::Continue;
+
+        for &code in keys {
+            action = app.key(KeyEvent::new(code, KeyModifiers::NONE), 3);
+        }
+
+        action
+    }
+
+    fn control(app: &mut App, c: char) -> Action {
+        app.key(KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL), 3)
+    }
+
+    fn type_in(app: &mut App, text: &str) {
+        for c in text.chars() {
+            press(app, &[KeyCode::Char(c)]);
+        }
+    }
+
+    fn numbers(app: &App) -> Vec<usize> {
+        app.hits.iter().map(|hit| hit.number).collect()
+    }
+
+    #[test]
+    fn results_follow_the_query() {
+        let mut app = app(&["--interactive", "poem.txt"]);
+
+        // no query yet, so every line
+        assert_eq!(9, app.hits.len());
+
+        type_in(&mut app, "nobody");
+        assert_eq!(vec![1, 2], numbers(&app));
+
+        type_in(&mut app, "!");
+        assert_eq!(vec![1], numbers(&app));
+
+        press(&mut app, &[KeyCode::Backspace]);
+        assert_eq!(vec![1, 2], numbers(&app));
+        assert_eq!(vec![4..10], app.hits[0].matches);
+    }
+
+    #[test]
+    fn case_can_be_toggled() {
+        let mut app = app(&["--interactive", "-e", "how", "poem.txt"]);
+        assert!(app.hits.is_empty());
+
+        control(&mut app, 't');
+        assert_eq!(vec![6, 7], numbers(&app));
+
+        control(&mut app, 't');
+        assert!(app.hits.is_empty());
+    }
+
+    #[test]
+    fn bad_regexes_keep_the_last_results() {
+        let mut app = app(&["--interactive", "-E", "-e", "fr", "poem.txt"]);
+        assert_eq!(vec![7], numbers(&app));
+
+        type_in(&mut app, "(");
+        assert!(app.error.is_some());
+        assert_eq!(vec![7], numbers(&app));
+
+        type_in(&mut app, "o)g");
+        assert!(app.error.is_none());
+        assert_eq!(vec![7], numbers(&app));
+    }
+
+    #[test]
+    fn scrolling_keeps_the_selection_on_screen() {
+        let mut app = app(&["--interactive", "poem.txt"]);
+        let mut screen = Vec::new();
+
+        press(&mut app, &[KeyCode::PageDown, KeyCode::Down]);
+        assert_eq!(4, app.selected);
+
+        // five rows is three hits between the prompt and the status line
+        app.render(&mut screen, 40, 5).unwrap();
+        assert_eq!(2, app.scroll);
+
+        press(&mut app, &[KeyCode::End]);
+        assert_eq!(8, app.selected);
+        press(&mut app, &[KeyCode::Home, KeyCode::Up]);
+        assert_eq!(0, app.selected);
+
+        app.render(&mut screen, 40, 5).unwrap();
+        assert_eq!(0, app.scroll);
+    }
+
+    #[test]
+    fn lines_can_be_jumped_to() {
+        let mut app = app(&["--interactive", "-e", "o", "poem.txt"]);
+        assert_eq!(vec![1, 2, 3, 4, 6, 7, 8, 9], numbers(&app));
+
+        control(&mut app, 'g');
+        press(&mut app, &[KeyCode::Char('5'), KeyCode::Enter]);
+        assert_eq!(6, app.hits[app.selected].number);
+        assert_eq!(Prompt::Query, app.prompt);
+
+        // past the end stops at the last line
+        control(&mut app, 'g');
+        type_in(&mut app, "99");
+        press(&mut app, &[KeyCode::Enter]);
+        assert_eq!(9, app.hits[app.selected].number);
+    }
+
+    #[test]
+    fn enter_picks_and_escape_quits() {
+        let mut app = app(&["--interactive", "poem.txt"]);
+
+        assert_eq!(Action::Print, press(&mut app, &[KeyCode::Enter]));
+        assert_eq!(Action::Quit, press(&mut app, &[KeyCode::Esc]));
+        assert_eq!(Action::Quit, control(&mut app, 'c'));
+
+        // escape only backs out of going to a line
+        control(&mut app, 'g');
+        assert_eq!(Action::Continue, press(&mut app, &[KeyCode::Esc]));
+    }
+
+    #[test]
+    fn no_terminal_is_a_usage_error() {
+        let poem = File::open("poem.txt").unwrap();
+
+        let error = check_terminal(&poem, &poem).unwrap_err();
+        assert!(matches!(error, MinigrepError::Usage(ConfigError::Requires("--interactive", "a terminal"))));
+        assert_eq!(2, error.exit_code());
+    }
+}
@@ -1,101 +1,520 @@
-use std::error::Error;
-use std::fs;
-use std::env;
-
-pub struct Config {
-    pub query: String,
-    pub filename: String,
-    pub case_sensitive: bool,
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write};
+use std::path::Path;
+
+mod config;
+mod decompress;
+mod error;
+mod follow;
+mod fuzzy;
+mod interactive;
+mod matcher;
+mod mmap;
+mod output;
+mod parallel;
+mod replace;
+mod searcher;
+mod walk;
+
+pub use config::{ColorChoice, Config, ConfigError, USAGE};
+pub use error::MinigrepError;
+pub use matcher::{BuildError, Matcher, Submatch};
+pub use parallel::ordered_map;
+pub use searcher::{Line, Searcher, Sink};
+
+use memmap2::Mmap;
+use output::Printer;
+use searcher::read_lines;
+use walk::Input;
+
+// Score every line of `reader` against `queries` for --fuzzy, offering the
+// ones that match at all to `top`. A line scores as well as it matches its
+// best query.
+fn search_fuzzy<R, T, F>(queries: &[String], reader: R, top: &mut fuzzy::Top<T>, mut item: F) -> io::Result<()>
+where
+    R: BufRead,
+    F: FnMut(usize, u64, &str) -> T,
+{
+    read_lines(reader, |number, offset, _, text| {
+        if let Some(score) = queries.iter().filter_map(|query| fuzzy::score(query, text)).max() {
+            top.offer(score, || item(number, offset, text));
+        }
+        Ok(true)
+    })
 }
 
-impl Config {
-    pub fn new(mut args: std::env::Args) -> Result<Config, &'static str> {
-        // let's skip over the first argument which is always
-        // the filename itself
-        args.next();
+// The name grep uses for stdin in its output
+const STDIN_NAME: &str = "(standard input)";
+
+// Search everything `config` asks for, printing what we find. Returns whether
+// anything was selected, which is what decides minigrep's exit code.
+pub fn run(config: Config) -> Result<bool, MinigrepError> {
+    let matcher = Matcher::new(&config)?;
+    let inputs = walk::inputs(&config);
+
+    if config.interactive {
+        return interactive::run(&config, &inputs);
+    }
+
+    let stdout = io::stdout();
+    let color = match config.color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => stdout.is_terminal(),
+    };
+
+    // counts don't have context to separate
+    let (before, after) = if config.count {
+        (0, 0)
+    } else {
+        (config.before_context, config.after_context)
+    };
+
+    let out: Box<dyn Write> = if config.quiet {
+        Box::new(io::sink())
+    } else {
+        Box::new(BufWriter::new(stdout.lock()))
+    };
+
+    let mut selected = false;
+    // the first file we couldn't search, if any
+    let mut failed = None;
+    let mut printer = Printer::new(out, config.line_number, before, after)
+        // like grep, only name the file when there could be more than one
+        .show_filename(config.recursive || config.filenames.len() > 1)
+        .color(color && !config.json)
+        .only_matching(config.only_matching)
+        .json(config.json);
+
+    if config.follow {
+        // only stops if something goes wrong
+        follow::run(&config, &matcher, &inputs, &mut printer)?;
+    } else if let Some(limit) = config.fuzzy {
+        // the best lines could be anywhere, so there's nothing to print until
+        // every input has been read
+        let mut top = fuzzy::Top::new(limit);
+
+        for (i, input) in inputs.iter().enumerate() {
+            let searched = open(input, config.decompress).and_then(|reader| match reader {
+                Some(reader) => search_fuzzy(&config.patterns, reader, &mut top, |number, offset, text| {
+                    (i, number, offset, text.to_string())
+                }),
+                None => Ok(()),
+            });
+
+            if let Err(e) = searched {
+                keep_going(&mut failed, blame(input, e))?;
+            }
+        }
+
+        for (score, (i, number, offset, text)) in top.into_sorted() {
+            printer.start_file(display_name(&inputs[i]))?;
+            printer.ranked(score, &Line {
+                number,
+                offset,
+                text: &text,
+                bytes: text.as_bytes(),
+                matches: &[],
+                matched: true,
+            })?;
+            selected = true;
+        }
+    } else if config.jobs > 1 && inputs.len() > 1 && !config.quiet {
+        // each file is searched into its own buffer on the pool, and the
+        // buffers are printed in order
+        let template = printer.buffered();
 
-        let query = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a query string"),
-        };
+        parallel::ordered_map(&inputs, config.jobs, |input| {
+            let mut file = template.buffered();
+            search_input(&config, &matcher, input, &mut file)
+                .map(|found| (file, found))
+                .map_err(|e| blame(input, e))
+        }, |result| match result {
+            Ok((file, found)) => {
+                selected |= found;
+                printer.append(file).map_err(MinigrepError::from)
+            },
+            Err(e) => keep_going(&mut failed, e),
+        })?;
+    } else {
+        // one at a time we can print as we go, which matters for stdin
+        for input in &inputs {
+            match search_input(&config, &matcher, input, &mut printer) {
+                Ok(found) => selected |= found,
+                Err(e) => keep_going(&mut failed, blame(input, e))?,
+            }
 
-        let filename = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a file name"),
-        };
+            if selected && config.quiet {
+                break;
+            }
+        }
+    }
 
-        let case_sensitive = env::var("CASE_INSENSITIVE").is_err();
+    printer.finish()?;
+    printer.into_inner().flush()?;
 
-        Ok(Config { query, filename, case_sensitive })
+    // like grep, -q finding something is all that matters
+    match failed {
+        Some(e) if !(config.quiet && selected) => Err(e),
+        _ => Ok(selected),
     }
 }
 
-fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
-    contents.lines()
-        .filter(|line| line.contains(query))
-        .collect()
+// Open something to search, decompressing it if need be, or give back None
+// if it should be skipped. Files are decompressed whenever they look
+// compressed, but stdin only with `force`, which also makes it an error for
+// anything not to be compressed.
+fn open(input: &Input, force: bool) -> io::Result<Option<Box<dyn BufRead>>> {
+    if input.is_stdin() {
+        let stdin = io::stdin().lock();
+
+        if force {
+            return decompress::reader(stdin, true).map(Some);
+        }
+
+        return Ok(Some(Box::new(stdin)));
+    }
+
+    // a file we stumbled on while walking isn't worth stopping the whole
+    // search over
+    let skip = |e: io::Error| {
+        if input.walked {
+            eprintln!("minigrep: {}: {}", input.path.display(), e);
+            Ok(None)
+        } else {
+            Err(e)
+        }
+    };
+
+    let file = match File::open(&input.path) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => return skip(e),
+    };
+
+    let mut reader = match decompress::reader(file, force) {
+        Ok(reader) => reader,
+        Err(e) => return skip(e),
+    };
+
+    // compressed files look binary, so it's what's inside that counts. This
+    // is also the first we hear of a corrupt one.
+    let start = match reader.fill_buf() {
+        Ok(start) => start,
+        Err(e) => return skip(e),
+    };
+
+    if input.walked && walk::is_binary(start) {
+        return Ok(None);
+    }
+
+    Ok(Some(reader))
+}
+
+// Where the lines we search come from
+enum Source {
+    Stream(Box<dyn BufRead>),
+    // a whole file in memory, searched by jumping between matches
+    Mapped(Mmap),
 }
 
-fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
-    let mut results = Vec::new();
-    let query = query.to_lowercase();
-    
-    for line in contents.lines() {
-        if line.to_lowercase().contains(&query) {
-            results.push(line);
+// Memory map `input` if `searcher` can search it whole: a plain text file,
+// not compressed, searched for plain strings, where only the lines that
+// match matter (so no -v or context). Otherwise give back None, and it can
+// be streamed instead.
+fn map(config: &Config, searcher: &Searcher, matcher: &Matcher, input: &Input) -> io::Result<Option<Mmap>> {
+    if !config.mmap || input.is_stdin() || config.decompress {
+        return Ok(None);
+    }
+
+    // anything that goes wrong here is left for `open` to find (and report)
+    // again
+    let file = match File::open(&input.path) {
+        Ok(file) => file,
+        Err(_) => return Ok(None),
+    };
+
+    let contents = match searcher.map(matcher, &file) {
+        Some(contents) => contents,
+        None => return Ok(None),
+    };
+
+    if decompress::Format::detect(&contents).is_some() || input.walked && walk::is_binary(&contents) {
+        return Ok(None);
+    }
+
+    Ok(Some(contents))
+}
+
+// Search `source` with `searcher`, whichever way the lines come
+fn search_source<S>(searcher: &Searcher, matcher: &Matcher, source: Source, sink: &mut S) -> io::Result<()>
+where
+    S: Sink + ?Sized,
+{
+    match source {
+        Source::Stream(reader) => searcher.search_buffered(matcher, reader, sink),
+        Source::Mapped(contents) => searcher.search_slice(matcher, &contents, sink),
+    }
+}
+
+// Whether the file at `path` starts like something compressed
+fn is_compressed(path: &Path) -> io::Result<bool> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    Ok(decompress::Format::detect(reader.fill_buf()?).is_some())
+}
+
+// Like grep, a file we can't search doesn't stop us searching the rest. The
+// first error is kept in `failed` to be returned once we're done, and any
+// after it are reported as they come. Having nowhere left to print is
+// another matter, though.
+fn keep_going(failed: &mut Option<MinigrepError>, e: MinigrepError) -> Result<(), MinigrepError> {
+    if let MinigrepError::Io(_, ref io) = e {
+        if io.kind() == io::ErrorKind::BrokenPipe {
+            return Err(e);
         }
     }
 
-    results
+    match failed {
+        None => *failed = Some(e),
+        Some(_) => eprintln!("minigrep: {}", e),
+    }
+
+    Ok(())
 }
 
-pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let contents = fs::read_to_string(config.filename)?;
+// Put the blame for an error on `input`, unless it's stdin
+fn blame(input: &Input, e: io::Error) -> MinigrepError {
+    let path = if input.is_stdin() { None } else { Some(input.path.as_path()) };
 
-    let results = if config.case_sensitive {
-        search(&config.query, &contents)
+    MinigrepError::io(path, e)
+}
+
+// What to call an input in what we print
+fn display_name(input: &Input) -> String {
+    if input.is_stdin() {
+        STDIN_NAME.to_string()
     } else {
-        search_case_insensitive(&config.query, &contents)
+        input.path.display().to_string()
+    }
+}
+
+// Search one input, printing what we find (or how much of it there was) to
+// `printer`. Returns whether anything was selected: a matching line, or with
+// -L the file itself.
+fn search_input<W: Write>(
+    config: &Config,
+    matcher: &Matcher,
+    input: &Input,
+    printer: &mut Printer<W>,
+) -> io::Result<bool> {
+    if let (Some(replacement), true) = (&config.replace, config.in_place) {
+        // there's nowhere to put a rewritten stdin
+        if input.is_stdin() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "can't rewrite standard input in place"));
+        }
+
+        // open it first anyway, to skip the same files searching would
+        if open(input, false)?.is_none() {
+            return Ok(false);
+        }
+
+        if is_compressed(&input.path)? {
+            let e = io::Error::new(io::ErrorKind::InvalidInput, "can't rewrite compressed files in place");
+
+            if input.walked {
+                eprintln!("minigrep: {}: {}", input.path.display(), e);
+                return Ok(false);
+            }
+
+            return Err(e);
+        }
+
+        return replace::rewrite(&input.path, matcher, replacement, config.backup.as_deref());
+    }
+
+    // only printing lines needs the ones around them, for context
+    let listing = config.count || config.files_with_matches || config.files_without_match;
+    let context = !listing && (config.before_context > 0 || config.after_context > 0);
+    let searcher = Searcher::new().invert_match(config.invert_match).context(context);
+
+    let source = match map(config, &searcher, matcher, input)? {
+        Some(contents) => Source::Mapped(contents),
+        None => match open(input, config.decompress)? {
+            Some(reader) => Source::Stream(reader),
+            None => return Ok(false),
+        },
     };
-    
-    for line in results {
-        println!("{}", line);
+
+    printer.start_file(display_name(input))?;
+
+    // -q only needs to see one line
+    let max = if config.quiet { Some(1) } else { config.max_count };
+    let mut selected = 0;
+
+    if config.files_with_matches || config.files_without_match {
+        // one match settles it
+        search_source(&searcher, matcher, source, &mut |line: &Line| {
+            if line.matched {
+                selected += 1;
+            }
+            Ok(selected == 0)
+        })?;
+
+        let listed = (selected > 0) == config.files_with_matches;
+        if listed {
+            printer.path()?;
+        }
+
+        printer.end_file()?;
+        return Ok(listed);
     }
 
-    Ok(())
+    if config.count {
+        search_source(&searcher, matcher, source, &mut |line: &Line| {
+            // -m0 means we shouldn't count even the first line
+            if max.is_some_and(|max| selected >= max) {
+                return Ok(false);
+            }
+
+            if line.matched {
+                selected += 1;
+            }
+            Ok(max.is_none_or(|max| selected < max))
+        })?;
+
+        printer.count(selected)?;
+    } else {
+        search_source(&searcher, matcher, source, &mut |line: &Line| {
+            if max.is_some_and(|max| selected >= max) {
+                // like grep, we still print the last match's trailing context
+                printer.line(&Line { matches: &[], matched: false, ..*line })?;
+            } else {
+                if line.matched {
+                    selected += 1;
+                }
+
+                print_line(config, matcher, printer, line)?;
+            }
+
+            Ok(max.is_none_or(|max| selected < max) || printer.in_context())
+        })?;
+    }
+
+    printer.end_file()?;
+    Ok(selected > 0)
+}
+
+// Print a line the way `config` asks, with its matches replaced for
+// --replace
+fn print_line<W: Write>(config: &Config, matcher: &Matcher, printer: &mut Printer<W>, line: &Line) -> io::Result<()> {
+    match &config.replace {
+        Some(replacement) if line.matched && replace::applies(matcher, line.text) => {
+            let (text, matches) = replace::replace_all(matcher, line.text, replacement);
+            printer.line(&Line { text: &text, bytes: text.as_bytes(), matches: &matches, ..*line })
+        },
+        _ => printer.line(line),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn lines(expected: &[(usize, &str)]) -> Vec<(usize, String)> {
+        expected.iter().map(|&(number, line)| (number, line.to_string())).collect()
+    }
+
+    // Search poem.txt with the given arguments, returning what was printed
+    // and whether anything was selected
+    fn search_poem(args: &[&str]) -> (String, bool) {
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+        let config = Config::parse(None, args, false).unwrap();
+        let input = Input { path: "poem.txt".into(), walked: false };
+
+        let mut printer = Printer::new(Vec::new(), config.line_number, config.before_context, config.after_context);
+        let selected = search_input(&config, &Matcher::new(&config).unwrap(), &input, &mut printer).unwrap();
+
+        (String::from_utf8(printer.into_inner()).unwrap(), selected)
+    }
+
     #[test]
-    fn one_result() {
-        let query = "duct";
-        let contents = "\
-Rust:
-safe, fast, productive.
-Pick three.
-Duct tape.";
+    fn fuzzy_ranks_the_closest_lines_first() {
+        let poem = std::fs::read("poem.txt").unwrap();
+        let mut top = fuzzy::Top::new(2);
 
+        search_fuzzy(&["nobdy".to_string()], &poem[..], &mut top, |number, _, text| {
+            (number, text.to_string())
+        }).unwrap();
+
+        // both lines have the whole word, so they tie and keep their order
+        let ranked: Vec<_> = top.into_sorted().into_iter().map(|(_, line)| line).collect();
         assert_eq!(
-            vec!["safe, fast, productive."],
-            search(query, contents)
+            lines(&[(1, "I'm nobody! Who are you?"), (2, "Are you nobody, too?")]),
+            ranked
         );
     }
 
     #[test]
-    fn case_insensitive() {
-        let query = "rUsT";
-        let contents = "\
-Rust:
-safe, fast, productive.
-Pick three.
-Trust me.";
-
+    fn max_count_still_prints_trailing_context() {
         assert_eq!(
-            vec!["Rust:", "Trust me."],
-            search_case_insensitive(query, contents)
+            ("1:I'm nobody! Who are you?\n2-Are you nobody, too?\n".to_string(), true),
+            search_poem(&["-n", "-m1", "-A1", "nobody", "poem.txt"])
         );
+        assert_eq!(("0\n".to_string(), false), search_poem(&["-c", "-m0", "nobody", "poem.txt"]));
+    }
+
+    #[test]
+    fn files_with_and_without_matches() {
+        assert_eq!(("poem.txt\n".to_string(), true), search_poem(&["-l", "frog", "poem.txt"]));
+        assert_eq!((String::new(), false), search_poem(&["-l", "newt", "poem.txt"]));
+        assert_eq!(("poem.txt\n".to_string(), true), search_poem(&["-L", "newt", "poem.txt"]));
+        assert_eq!((String::new(), false), search_poem(&["-L", "frog", "poem.txt"]));
+    }
+
+    #[test]
+    fn corrupt_files_are_skipped_when_walked() {
+        let dir = std::env::temp_dir().join(format!("minigrep_corrupt_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // gzip's magic bytes, then nonsense
+        std::fs::write(dir.join("a.gz"), b"\x1f\x8bnot really gzip").unwrap();
+        std::fs::write(dir.join("b.txt"), "a frog\n").unwrap();
+
+        let args = ["-r", "-j1", "frog", dir.to_str().unwrap()];
+        let config = Config::parse(None, args.iter().map(|arg| arg.to_string()).collect(), false).unwrap();
+        let matcher = Matcher::new(&config).unwrap();
+        let mut printer = Printer::new(Vec::new(), false, 0, 0);
+
+        let mut selected = false;
+        for input in walk::inputs(&config) {
+            selected |= search_input(&config, &matcher, &input, &mut printer).unwrap();
+        }
+        assert!(selected);
+
+        // but named on the command line, it's an error
+        let input = Input { path: dir.join("a.gz"), walked: false };
+        assert!(search_input(&config, &matcher, &input, &mut printer).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn empty_matches_are_replaced_like_in_place_would() {
+        let (printed, _) = search_poem(&["-E", "--replace=> ", "^", "poem.txt"]);
+
+        assert_eq!(Some("> I'm nobody! Who are you?"), printed.lines().next());
+        assert!(printed.lines().all(|line| line.starts_with("> ")));
+    }
+
+    #[test]
+    fn files_after_a_missing_one_are_still_searched() {
+        let run = |args: &[&str]| super::run(Config::parse(None, args.iter().map(|arg| arg.to_string()).collect(), false).unwrap());
+
+        // -q stops at the first match, which has to be in poem.txt
+        assert!(run(&["-q", "frog", "no/such/file", "poem.txt"]).unwrap());
+
+        let error = run(&["-q", "toad", "no/such/file", "poem.txt"]).unwrap_err();
+        assert_eq!(4, error.exit_code());
+        assert!(error.to_string().starts_with("no/such/file: "), "{}", error);
     }
 }
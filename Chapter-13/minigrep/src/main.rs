@@ -1,18 +1,34 @@
 use std::env;
 use std::process;
 
-use minigrep;
-use minigrep::Config;
+use minigrep::{Config, ConfigError, MinigrepError, USAGE};
 
 fn main() {
     let config = Config::new(env::args()).unwrap_or_else(|err| {
+        if let ConfigError::Help | ConfigError::Version = err {
+            println!("{}", err);
+            process::exit(0);
+        }
+
+        let err = MinigrepError::from(err);
         eprintln!("Problem parsing arguments: {}", err);
-        process::exit(1);
+
+        // a missing file or a bad pattern isn't fixed by reading the usage
+        if let MinigrepError::Usage(_) = err {
+            eprintln!("{}", USAGE);
+        }
+
+        process::exit(err.exit_code());
     });
 
-    if let Err(e) = minigrep::run(config) {
-        eprintln!("Application error: {}", e);
-        process::exit(1);
+    // 0 if something was found and 1 if not, like grep, but each kind of
+    // error has its own code from 2 up (see MinigrepError::exit_code)
+    match minigrep::run(config) {
+        Ok(true) => process::exit(0),
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("Application error: {}", e);
+            process::exit(e.exit_code());
+        },
     }
 }
-
@@ -0,0 +1,248 @@
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+use aho_corasick::{AhoCorasick, MatchKind};
+use regex::Regex;
+
+use crate::config::{self, Config};
+
+/// A stretch of a line that matched, and which of the patterns matched it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Submatch {
+    /// The byte range of the match in the line.
+    pub range: Range<usize>,
+    /// The index of the pattern that matched, into the patterns the matcher
+    /// was made from.
+    pub pattern: usize,
+}
+
+/// What a line has to contain for us to report it.
+///
+/// However many patterns there are, each line is only searched once.
+pub struct Matcher {
+    pub(crate) kind: Kind,
+}
+
+pub(crate) enum Kind {
+    // plain strings, all looked for at once by one automaton. The strings
+    // themselves are kept for searching whole files, in mmap.rs.
+    Fixed { patterns: AhoCorasick, strings: Vec<Vec<u8>> },
+    // everything else, including plain strings regardless of case. Case
+    // sensitivity is already baked into the regex, which has every pattern
+    // in a capture group of its own. `groups` holds the group number for each
+    // pattern.
+    Regex { regex: Regex, groups: Vec<usize> },
+}
+
+/// Why a matcher couldn't be made.
+#[derive(Debug)]
+pub enum BuildError {
+    /// A pattern isn't a valid regular expression, or the regex is too big.
+    Regex(regex::Error),
+    /// There are too many (or too long) plain strings.
+    Fixed(aho_corasick::BuildError),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::Regex(e) => write!(f, "Invalid regular expression: {}", e),
+            BuildError::Fixed(e) => write!(f, "Can't search for these strings: {}", e),
+        }
+    }
+}
+
+impl Error for BuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BuildError::Regex(e) => Some(e),
+            BuildError::Fixed(e) => Some(e),
+        }
+    }
+}
+
+impl Matcher {
+    /// The matcher for everything `config` asks for.
+    pub fn new(config: &Config) -> Result<Matcher, BuildError> {
+        match &config.regex {
+            Some(regex) => Ok(Matcher::from_regex(regex.clone(), config.patterns.len())),
+            None => Matcher::fixed(&config.patterns),
+        }
+    }
+
+    /// Match any of `patterns` exactly, case and all.
+    pub fn fixed<P: AsRef<[u8]>>(patterns: &[P]) -> Result<Matcher, BuildError> {
+        let strings = patterns.iter().map(|pattern| pattern.as_ref().to_vec()).collect();
+
+        // like a regex alternation, earlier patterns win when two could match
+        // at the same place
+        let patterns = AhoCorasick::builder()
+            .match_kind(MatchKind::LeftmostFirst)
+            .build(patterns)
+            .map_err(BuildError::Fixed)?;
+
+        Ok(Matcher { kind: Kind::Fixed { patterns, strings } })
+    }
+
+    /// Match any of the regular expressions `patterns`. Flags like `(?i)` for
+    /// ignoring case only apply to the pattern they're in.
+    pub fn regex<P: AsRef<str>>(patterns: &[P]) -> Result<Matcher, BuildError> {
+        let regex = Regex::new(&config::alternation(patterns)).map_err(BuildError::Regex)?;
+
+        Ok(Matcher::from_regex(regex, patterns.len()))
+    }
+
+    // `regex` has to come from `config::alternation` of `count` patterns
+    fn from_regex(regex: Regex, count: usize) -> Matcher {
+        let groups = (0..count)
+            .map(|i| {
+                let name = config::pattern_group(i);
+                regex.capture_names().position(|group| group == Some(name.as_str())).unwrap()
+            })
+            .collect();
+
+        Matcher { kind: Kind::Regex { regex, groups } }
+    }
+
+    /// Whether `line` has a match anywhere in it.
+    pub fn is_match(&self, line: &str) -> bool {
+        match &self.kind {
+            Kind::Fixed { patterns, .. } => patterns.is_match(line),
+            // with no patterns at all the regex is empty, which would match
+            // everything
+            Kind::Regex { regex, groups } => !groups.is_empty() && regex.is_match(line),
+        }
+    }
+
+    /// Replace `found` with the parts of `line` that matched. Empty matches
+    /// (like from an empty pattern) aren't worth highlighting, so they're
+    /// left out.
+    pub fn find_all(&self, line: &str, found: &mut Vec<Submatch>) {
+        found.clear();
+
+        match &self.kind {
+            Kind::Fixed { patterns, .. } => {
+                found.extend(patterns.find_iter(line).map(|m| Submatch {
+                    range: m.range(),
+                    pattern: m.pattern().as_usize(),
+                }));
+            },
+            // no need to work out which group matched if there's only one
+            Kind::Regex { regex, groups } if groups.len() == 1 => {
+                found.extend(regex.find_iter(line).map(|m| Submatch { range: m.range(), pattern: 0 }));
+            },
+            Kind::Regex { regex, groups } => {
+                for captures in regex.captures_iter(line) {
+                    let whole = captures.get(0).unwrap();
+                    let pattern = groups.iter().position(|&group| captures.get(group).is_some());
+
+                    found.push(Submatch { range: whole.range(), pattern: pattern.unwrap_or(0) });
+                }
+            },
+        }
+
+        found.retain(|submatch| !submatch.range.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(args: &[&str]) -> Matcher {
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+
+        Matcher::new(&Config::parse(None, args, false).unwrap()).unwrap()
+    }
+
+    fn ranges(matcher: &Matcher, line: &str) -> Vec<Range<usize>> {
+        let mut found = Vec::new();
+        matcher.find_all(line, &mut found);
+        found.into_iter().map(|submatch| submatch.range).collect()
+    }
+
+    #[test]
+    fn matches_have_byte_ranges() {
+        assert_eq!(vec![1..3, 6..8], ranges(&matcher(&["at"]), "hat, cat"));
+        assert_eq!(vec![0..2, 5..7], ranges(&matcher(&["-E", r"\w\w"]), "ab - cd"));
+
+        // an empty pattern matches, but there's nothing to highlight
+        let empty = matcher(&[""]);
+        assert!(empty.is_match("anything"));
+        assert!(ranges(&empty, "anything").is_empty());
+    }
+
+    #[test]
+    fn case_insensitive_ranges_point_into_the_original() {
+        // İ takes two bytes, and its lowercase form is two characters
+        assert_eq!(vec![4..11], ranges(&matcher(&["-i", "straße"]), "İ, STRAßE"));
+    }
+
+    #[test]
+    fn case_folding_is_unicode_aware() {
+        let ignoring_case = |query: &str, line: &str| matcher(&["-i", query]).is_match(line);
+
+        // capital sharp s, the Kelvin sign and final sigma all fold
+        assert!(ignoring_case("straße", "STRAẞE"));
+        assert!(ignoring_case("kelvin", "\u{212A}ELVIN"));
+        assert!(ignoring_case("ΣΟΦΟΣ", "σοφος"));
+        assert!(ignoring_case("σοφοσ", "σοφος"));
+
+        // but only simple folding: ß isn't ss, and dotted İ isn't i
+        assert!(!ignoring_case("strasse", "straße"));
+        assert!(!ignoring_case("i", "İ"));
+    }
+
+    #[test]
+    fn every_pattern_is_found_in_one_pass() {
+        let line = "a frog in a bog";
+        let expected = vec![
+            Submatch { range: 2..6, pattern: 1 },
+            Submatch { range: 12..15, pattern: 0 },
+        ];
+
+        for args in &[
+            &["-e", "bog", "-e", "frog"][..],
+            &["-i", "-e", "BOG", "-e", "Frog"],
+            &["-E", "-e", "b.g", "-e", "fr(o)g"],
+        ] {
+            let mut found = Vec::new();
+            matcher(args).find_all(line, &mut found);
+
+            assert_eq!(expected, found, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn matchers_can_be_made_without_a_config() {
+        let mut found = Vec::new();
+
+        Matcher::fixed(&["bog", "frog"]).unwrap().find_all("a frog in a bog", &mut found);
+        assert_eq!(vec![Submatch { range: 2..6, pattern: 1 }, Submatch { range: 12..15, pattern: 0 }], found);
+
+        // (?i) only applies to the pattern it's in
+        let matcher = Matcher::regex(&["(?i)FR.G", "BOG"]).unwrap();
+        assert!(matcher.is_match("a frog"));
+        assert!(!matcher.is_match("a bog"));
+
+        assert!(matches!(Matcher::regex(&["fr(og"]), Err(BuildError::Regex(_))));
+    }
+
+    #[test]
+    fn no_patterns_match_nothing() {
+        let dir = std::env::temp_dir().join(format!("minigrep_matcher_{}", std::process::id()));
+        std::fs::write(&dir, "").unwrap();
+
+        for regex in &[false, true] {
+            let mut args = vec!["-f", dir.to_str().unwrap()];
+            if *regex {
+                args.push("-E");
+            }
+
+            assert!(!matcher(&args).is_match("anything"));
+        }
+
+        std::fs::remove_file(&dir).unwrap();
+    }
+}
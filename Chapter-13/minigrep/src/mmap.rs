@@ -0,0 +1,180 @@
+use std::fs::File;
+use std::io;
+
+use aho_corasick::AhoCorasick;
+use memchr::{memchr, memchr_iter, memmem, memrchr};
+use memmap2::Mmap;
+
+use crate::matcher::{Kind, Matcher};
+use crate::searcher::{Line, Sink};
+
+// Finds where any of the queries first turns up in a whole file at once,
+// newlines and all
+pub enum Finder<'a> {
+    // memchr's SIMD substring search, for the usual single query. It's
+    // boxed since it's a lot bigger than a reference.
+    One(Box<memmem::Finder<'a>>),
+    Many(&'a AhoCorasick),
+}
+
+impl<'a> Finder<'a> {
+    // Only plain strings can be found in the raw bytes. Queries with a
+    // newline in them never match a line, but could match here, and ones
+    // with a replacement character can match where invalid UTF-8 was
+    // replaced, which isn't in the raw bytes at all.
+    pub fn new(matcher: &'a Matcher) -> Option<Finder<'a>> {
+        let (patterns, strings) = match &matcher.kind {
+            Kind::Fixed { patterns, strings } => (patterns, strings),
+            Kind::Regex { .. } => return None,
+        };
+
+        let replacement = "\u{FFFD}".as_bytes();
+        if strings.iter().any(|s| s.contains(&b'\n') || memmem::find(s, replacement).is_some()) {
+            return None;
+        }
+
+        match &strings[..] {
+            [string] => Some(Finder::One(Box::new(memmem::Finder::new(string)))),
+            _ => Some(Finder::Many(patterns)),
+        }
+    }
+
+    fn find(&self, haystack: &[u8]) -> Option<usize> {
+        match self {
+            Finder::One(finder) => finder.find(haystack),
+            Finder::Many(patterns) => patterns.find(haystack).map(|m| m.start()),
+        }
+    }
+}
+
+// Map `file` into memory, so the whole of it can be searched as one slice
+// without reading it in first
+pub fn map(file: &File) -> io::Result<Mmap> {
+    // Safety: the mapping is only read, but if some other process truncates
+    // the file while we're searching it we could be killed by SIGBUS. grep
+    // and ripgrep take the same risk for the same speed.
+    unsafe { Mmap::map(file) }
+}
+
+// Search a whole file at once: `finder` jumps from match to match, and only
+// the lines the matches are on are ever looked at. That means only matching
+// lines are passed to `sink`, so this can't do -v or context.
+pub fn search<S: Sink + ?Sized>(matcher: &Matcher, finder: &Finder, contents: &[u8], sink: &mut S) -> io::Result<()> {
+    let mut submatches = Vec::new();
+    // the line number of the line starting at `counted_to`
+    let mut number = 1;
+    let mut counted_to = 0;
+    let mut at = 0;
+
+    while let Some(hit) = finder.find(&contents[at..]) {
+        let hit = at + hit;
+        let start = memrchr(b'\n', &contents[..hit]).map_or(0, |newline| newline + 1);
+        let end = memchr(b'\n', &contents[hit..]).map_or(contents.len(), |newline| hit + newline);
+
+        // an empty query can match after the last newline, where there's no
+        // line at all
+        if start == contents.len() {
+            break;
+        }
+
+        number += memchr_iter(b'\n', &contents[counted_to..start]).count();
+        counted_to = start;
+        at = end + 1;
+
+        // same line endings as str::lines
+        let bytes = &contents[start..end];
+        let bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+        let text = String::from_utf8_lossy(bytes);
+
+        // the bytes matched, but make sure the text does too, in case
+        // replacing invalid UTF-8 made a difference
+        if !matcher.is_match(&text) {
+            continue;
+        }
+
+        matcher.find_all(&text, &mut submatches);
+
+        let keep_going = sink.matched(&Line {
+            number,
+            offset: start as u64,
+            text: &text,
+            bytes,
+            matches: &submatches,
+            matched: true,
+        })?;
+
+        if !keep_going || at >= contents.len() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::Submatch;
+    use crate::{Config, Searcher};
+
+    // Every matching line's number, offset, text and matches, found both by
+    // streaming and from the whole buffer, which had better agree
+    fn both_ways(args: &[&str], contents: &[u8]) -> Vec<(usize, u64, String, Vec<Submatch>)> {
+        let argv = args.iter().map(|arg| arg.to_string()).collect();
+        let config = Config::parse(None, argv, false).unwrap();
+        let matcher = Matcher::new(&config).unwrap();
+        let finder = Finder::new(&matcher).unwrap();
+
+        let mut streamed = Vec::new();
+        Searcher::new().search_reader(&matcher, contents, &mut |line: &Line| {
+            if line.matched {
+                streamed.push((line.number, line.offset, line.text.to_string(), line.matches.to_vec()));
+            }
+            Ok(true)
+        }).unwrap();
+
+        let mut mapped = Vec::new();
+        search(&matcher, &finder, contents, &mut |line: &Line| {
+            mapped.push((line.number, line.offset, line.text.to_string(), line.matches.to_vec()));
+            Ok(true)
+        }).unwrap();
+
+        assert_eq!(streamed, mapped, "{:?}", args);
+        mapped
+    }
+
+    #[test]
+    fn matches_agree_with_streaming() {
+        let poem = std::fs::read("poem.txt").unwrap();
+
+        assert_eq!(2, both_ways(&["nobody"], &poem).len());
+        assert_eq!(6, both_ways(&["-e", "frog", "-e", "bog", "-e", "you"], &poem).len());
+        assert_eq!(0, both_ways(&["newt"], &poem).len());
+
+        // every line, but nothing after the last newline
+        assert_eq!(9, both_ways(&[""], &poem).len());
+    }
+
+    #[test]
+    fn line_ends_and_invalid_utf8_agree_with_streaming() {
+        let contents = b"frog\r\ncaf\xe9 frog\n\nno\nfrog frog";
+
+        let found = both_ways(&["frog"], contents);
+        assert_eq!(vec![1, 2, 5], found.iter().map(|line| line.0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn only_plain_strings_without_newlines_can_be_mapped() {
+        let finder = |args: &[&str]| {
+            let args = args.iter().map(|arg| arg.to_string()).collect();
+            let config = Config::parse(None, args, false).unwrap();
+            Finder::new(&Matcher::new(&config).unwrap()).is_some()
+        };
+
+        assert!(finder(&["frog"]));
+        assert!(!finder(&["-E", "fr.g"]));
+        assert!(!finder(&["-i", "frog"]));
+        assert!(!finder(&["a\nb"]));
+        assert!(!finder(&["caf\u{FFFD}"]));
+    }
+}
@@ -0,0 +1,621 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::json;
+
+use crate::{Line, Submatch};
+
+// The same colors grep uses by default
+const MATCH_COLOR: &str = "\x1b[1;31m";
+const FILENAME_COLOR: &str = "\x1b[35m";
+const NUMBER_COLOR: &str = "\x1b[32m";
+const SEPARATOR_COLOR: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+// Tallies for --json, for one file or for the whole search
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Stats {
+    searches: usize,
+    searches_with_match: usize,
+    matched_lines: usize,
+    matches: usize,
+}
+
+impl Stats {
+    fn add(&mut self, other: Stats) {
+        self.searches += other.searches;
+        self.searches_with_match += other.searches_with_match;
+        self.matched_lines += other.matched_lines;
+        self.matches += other.matches;
+    }
+}
+
+// A context line we're holding on to in case a match turns up soon
+struct Pending {
+    number: usize,
+    offset: u64,
+    text: String,
+    bytes: Vec<u8>,
+}
+
+// Prints the lines a search selects, grep style: matching lines as
+// `file:number:text`, surrounding context lines as `file-number-text`, and
+// `--` between groups of lines that aren't next to each other.
+//
+// With --json every line becomes a JSON message instead, between a `begin`
+// and `end` message for each file and a `summary` at the very end.
+pub struct Printer<W: Write> {
+    out: W,
+    line_number: bool,
+    show_filename: bool,
+    color: bool,
+    only_matching: bool,
+    json: bool,
+    before: usize,
+    after: usize,
+    // the name of the file we're printing lines from
+    path: String,
+    pending: VecDeque<Pending>,
+    // how many more lines to print after the last match
+    after_left: usize,
+    // the last line number we printed in the current file
+    last_printed: Option<usize>,
+    // whether anything has been printed at all, across every file
+    printed_any: bool,
+    // what we've found in the current file, and in every file before it
+    file_stats: Stats,
+    stats: Stats,
+}
+
+impl<W: Write> Printer<W> {
+    pub fn new(out: W, line_number: bool, before: usize, after: usize) -> Printer<W> {
+        Printer {
+            out,
+            line_number,
+            show_filename: false,
+            color: false,
+            only_matching: false,
+            json: false,
+            before,
+            after,
+            path: String::new(),
+            pending: VecDeque::with_capacity(before),
+            after_left: 0,
+            last_printed: None,
+            printed_any: false,
+            file_stats: Stats::default(),
+            stats: Stats::default(),
+        }
+    }
+
+    // Put the file name in front of every line
+    pub fn show_filename(mut self, show_filename: bool) -> Printer<W> {
+        self.show_filename = show_filename;
+        self
+    }
+
+    // Highlight matches (and the bits around them) with ANSI escapes
+    pub fn color(mut self, color: bool) -> Printer<W> {
+        self.color = color;
+        self
+    }
+
+    // Print each match on its own line instead of the lines they're in. There's
+    // no context in this mode, same as grep.
+    pub fn only_matching(mut self, only_matching: bool) -> Printer<W> {
+        self.only_matching = only_matching;
+        self
+    }
+
+    // Print JSON messages instead of text. Colors and -o don't apply, since
+    // the messages already say where each match is.
+    pub fn json(mut self, json: bool) -> Printer<W> {
+        self.json = json;
+        self
+    }
+
+    // A printer with the same settings that prints into memory, so a file can
+    // be searched on another thread. Hand it to `append` when it's done.
+    pub fn buffered(&self) -> Printer<Vec<u8>> {
+        Printer::new(Vec::new(), self.line_number, self.before, self.after)
+            .show_filename(self.show_filename)
+            .color(self.color)
+            .only_matching(self.only_matching)
+            .json(self.json)
+    }
+
+    // Print what a `buffered` printer printed, with a separator in front if
+    // it needs one.
+    pub fn append(&mut self, file: Printer<Vec<u8>>) -> io::Result<()> {
+        self.stats.add(file.stats);
+
+        if file.out.is_empty() {
+            return Ok(());
+        }
+
+        // a new file never follows straight on from the last one
+        self.last_printed = None;
+        self.separate(1)?;
+        self.printed_any = true;
+
+        self.out.write_all(&file.out)
+    }
+
+    // Get ready to print lines from another file
+    pub fn start_file(&mut self, path: String) -> io::Result<()> {
+        self.path = path;
+        self.pending.clear();
+        self.after_left = 0;
+        self.last_printed = None;
+        self.file_stats = Stats { searches: 1, ..Stats::default() };
+
+        if self.json {
+            self.message(json!({ "type": "begin", "data": { "path": self.path } }))?;
+        }
+
+        Ok(())
+    }
+
+    // Done with the current file
+    pub fn end_file(&mut self) -> io::Result<()> {
+        if self.file_stats.matched_lines > 0 {
+            self.file_stats.searches_with_match = 1;
+        }
+
+        self.stats.add(self.file_stats);
+
+        if self.json {
+            let stats = self.file_stats;
+            self.message(json!({
+                "type": "end",
+                "data": {
+                    "path": self.path,
+                    "stats": { "matched_lines": stats.matched_lines, "matches": stats.matches },
+                },
+            }))?;
+        }
+
+        Ok(())
+    }
+
+    // Done with every file
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.json {
+            let stats = self.stats;
+            self.message(json!({
+                "type": "summary",
+                "data": {
+                    "stats": {
+                        "searches": stats.searches,
+                        "searches_with_match": stats.searches_with_match,
+                        "matched_lines": stats.matched_lines,
+                        "matches": stats.matches,
+                    },
+                },
+            }))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn line(&mut self, line: &Line) -> io::Result<()> {
+        if line.matched {
+            self.file_stats.matched_lines += 1;
+            self.file_stats.matches += line.matches.len();
+        }
+
+        if self.only_matching && !self.json {
+            if line.matched {
+                for submatch in line.matches {
+                    let span = &submatch.range;
+                    let whole = Submatch { range: 0..span.len(), pattern: submatch.pattern };
+                    self.print(&Line {
+                        number: line.number,
+                        offset: line.offset + span.start as u64,
+                        text: &line.text[span.clone()],
+                        bytes: line.text[span.clone()].as_bytes(),
+                        matches: std::slice::from_ref(&whole),
+                        matched: true,
+                    }, ':')?;
+                }
+            }
+
+            return Ok(());
+        }
+
+        if line.matched {
+            let first = self.pending.front().map_or(line.number, |pending| pending.number);
+            self.separate(first)?;
+
+            while let Some(pending) = self.pending.pop_front() {
+                self.print(&Line {
+                    number: pending.number,
+                    offset: pending.offset,
+                    text: &pending.text,
+                    bytes: &pending.bytes,
+                    matches: &[],
+                    matched: false,
+                }, '-')?;
+            }
+
+            self.print(line, ':')?;
+            self.after_left = self.after;
+        } else if self.after_left > 0 {
+            self.after_left -= 1;
+            self.print(line, '-')?;
+        } else if self.before > 0 {
+            if self.pending.len() == self.before {
+                self.pending.pop_front();
+            }
+
+            self.pending.push_back(Pending {
+                number: line.number,
+                offset: line.offset,
+                text: line.text.to_string(),
+                bytes: line.bytes.to_vec(),
+            });
+        }
+
+        Ok(())
+    }
+
+    // Print a line --fuzzy ranked, with its score in front
+    pub fn ranked(&mut self, score: u32, line: &Line) -> io::Result<()> {
+        let score = self.paint(NUMBER_COLOR, &score.to_string());
+        let separator = self.paint(SEPARATOR_COLOR, ":");
+        write!(self.out, "{}{}", score, separator)?;
+
+        self.print(line, ':')
+    }
+
+    // Print the current file's name on a line of its own, for -l and -L
+    pub fn path(&mut self) -> io::Result<()> {
+        let path = self.paint(FILENAME_COLOR, &self.path);
+        writeln!(self.out, "{}", path)
+    }
+
+    // Print how many lines matched in the current file, for -c
+    pub fn count(&mut self, count: usize) -> io::Result<()> {
+        self.file_stats.matched_lines = count;
+
+        if self.show_filename {
+            let path = self.paint(FILENAME_COLOR, &self.path);
+            let separator = self.paint(SEPARATOR_COLOR, ":");
+            writeln!(self.out, "{}{}{}", path, separator, count)
+        } else {
+            writeln!(self.out, "{}", count)
+        }
+    }
+
+    // Whether we're still printing context after a match
+    pub fn in_context(&self) -> bool {
+        self.after_left > 0
+    }
+
+    // Get what's been printed so far out, for when more is a while coming
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    // Print the `--` separator if line `first` doesn't follow straight on
+    // from what we printed last. Only needed when there's context to
+    // separate.
+    fn separate(&mut self, first: usize) -> io::Result<()> {
+        if self.before == 0 && self.after == 0 || self.only_matching || self.json {
+            return Ok(());
+        }
+
+        let gap = match self.last_printed {
+            // numbers only go backwards when --follow starts a file over
+            Some(last) => first != last + 1,
+            None => self.printed_any,
+        };
+
+        if gap {
+            let separator = self.paint(SEPARATOR_COLOR, "--");
+            writeln!(self.out, "{}", separator)?;
+        }
+
+        Ok(())
+    }
+
+    // Print a matching line (when `separator` is ':') or a context line
+    fn print(&mut self, line: &Line, separator: char) -> io::Result<()> {
+        self.last_printed = Some(line.number);
+        self.printed_any = true;
+
+        if self.json {
+            // offsets are into the bytes we read, which only differ from the
+            // text if it had invalid UTF-8 in it
+            let valid = line.bytes == line.text.as_bytes();
+
+            let submatches: Vec<_> = line.matches.iter()
+                .map(|submatch| {
+                    let span = &submatch.range;
+                    let (start, end) = if valid {
+                        (span.start, span.end)
+                    } else {
+                        (raw_offset(line.bytes, span.start), raw_offset(line.bytes, span.end))
+                    };
+
+                    json!({
+                        "match": &line.text[span.clone()],
+                        "start": start,
+                        "end": end,
+                        "pattern": submatch.pattern,
+                    })
+                })
+                .collect();
+
+            let mut data = json!({
+                "path": self.path,
+                "line_number": line.number,
+                "absolute_offset": line.offset,
+                "text": line.text,
+                "submatches": submatches,
+            });
+
+            // the text has U+FFFD where the bytes were invalid, so give the
+            // real thing too, the way ripgrep does
+            if !valid {
+                data["bytes"] = json!(BASE64.encode(line.bytes));
+            }
+
+            return self.message(json!({
+                "type": if separator == ':' { "match" } else { "context" },
+                "data": data,
+            }));
+        }
+
+        let separator = self.paint(SEPARATOR_COLOR, &separator.to_string());
+        let mut printed = String::new();
+
+        if self.show_filename {
+            printed += &self.paint(FILENAME_COLOR, &self.path);
+            printed += &separator;
+        }
+
+        if self.line_number {
+            printed += &self.paint(NUMBER_COLOR, &line.number.to_string());
+            printed += &separator;
+        }
+
+        if self.color {
+            let mut end = 0;
+
+            for submatch in line.matches {
+                let span = &submatch.range;
+                printed += &line.text[end..span.start];
+                printed += &self.paint(MATCH_COLOR, &line.text[span.clone()]);
+                end = span.end;
+            }
+
+            printed += &line.text[end..];
+        } else {
+            printed += line.text;
+        }
+
+        writeln!(self.out, "{}", printed)
+    }
+
+    // Write one JSON message on its own line
+    fn message(&mut self, message: serde_json::Value) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, &message)?;
+        writeln!(self.out)
+    }
+
+    fn paint(&self, color: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", color, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+// Where byte `offset` of the text `bytes` decodes to (lossily) falls in
+// `bytes` itself. Each run of invalid bytes became one U+FFFD, which is 3
+// bytes of text however many bytes it stands for.
+fn raw_offset(bytes: &[u8], offset: usize) -> usize {
+    let mut text_at = 0;
+    let mut raw_at = 0;
+
+    for chunk in bytes.utf8_chunks() {
+        let valid = chunk.valid().len();
+
+        if offset <= text_at + valid {
+            return raw_at + offset - text_at;
+        }
+
+        text_at += valid;
+        raw_at += valid;
+
+        if !chunk.invalid().is_empty() {
+            // matches can't start or end inside a character
+            text_at += char::REPLACEMENT_CHARACTER.len_utf8();
+            raw_at += chunk.invalid().len();
+        }
+    }
+
+    raw_at
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::Range;
+
+    // Feed lines 1 to `total` through a printer, with the ones in `matches`
+    // marked as matching, and return what it printed.
+    fn print(total: usize, matches: &[usize], before: usize, after: usize) -> String {
+        let mut printer = Printer::new(Vec::new(), true, before, after).show_filename(true);
+        printer.start_file("poem.txt".to_string()).unwrap();
+
+        for number in 1..=total {
+            let text = format!("line {}", number);
+            let matched = matches.contains(&number);
+            let line = Line { number, offset: 0, text: &text, bytes: text.as_bytes(), matches: &[], matched };
+            printer.line(&line).unwrap();
+        }
+
+        String::from_utf8(printer.into_inner()).unwrap()
+    }
+
+    fn print_one(printer: Printer<Vec<u8>>, text: &str, ranges: &[Range<usize>]) -> String {
+        let matches: Vec<Submatch> = ranges.iter()
+            .map(|range| Submatch { range: range.clone(), pattern: 0 })
+            .collect();
+        let matches = &matches[..];
+
+        let mut printer = printer.show_filename(true);
+        printer.start_file("poem.txt".to_string()).unwrap();
+        printer.line(&Line { number: 3, offset: 40, text, bytes: text.as_bytes(), matches, matched: true }).unwrap();
+        printer.end_file().unwrap();
+        printer.finish().unwrap();
+
+        String::from_utf8(printer.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn no_context_means_no_separators() {
+        assert_eq!(
+            "poem.txt:2:line 2\npoem.txt:5:line 5\n",
+            print(6, &[2, 5], 0, 0)
+        );
+    }
+
+    #[test]
+    fn context_groups_are_separated() {
+        assert_eq!(
+            "\
+poem.txt-1-line 1
+poem.txt:2:line 2
+poem.txt-3-line 3
+--
+poem.txt-6-line 6
+poem.txt:7:line 7
+poem.txt-8-line 8
+",
+            print(8, &[2, 7], 1, 1)
+        );
+    }
+
+    #[test]
+    fn overlapping_context_is_merged() {
+        assert_eq!(
+            "\
+poem.txt:2:line 2
+poem.txt-3-line 3
+poem.txt:4:line 4
+poem.txt-5-line 5
+poem.txt-6-line 6
+",
+            print(8, &[2, 4], 0, 2)
+        );
+    }
+
+    #[test]
+    fn appended_files_are_separated() {
+        let mut printer = Printer::new(Vec::new(), false, 1, 0).show_filename(true);
+
+        for text in &["one", "two"] {
+            let mut file = printer.buffered();
+            file.start_file(text.to_string()).unwrap();
+            file.line(&Line { number: 1, offset: 0, text, bytes: text.as_bytes(), matches: &[], matched: true }).unwrap();
+            file.end_file().unwrap();
+            printer.append(file).unwrap();
+        }
+
+        printer.append(printer.buffered()).unwrap();
+        assert_eq!(2, printer.stats.searches_with_match);
+
+        assert_eq!("one:one\n--\ntwo:two\n", String::from_utf8(printer.into_inner()).unwrap());
+    }
+
+    #[test]
+    fn color_highlights_matches() {
+        let printer = Printer::new(Vec::new(), true, 0, 0).color(true);
+
+        assert_eq!(
+            "\x1b[35mpoem.txt\x1b[0m\x1b[36m:\x1b[0m\x1b[32m3\x1b[0m\x1b[36m:\x1b[0m\
+a \x1b[1;31mfrog\x1b[0m, a \x1b[1;31mfrog\x1b[0m!\n",
+            print_one(printer, "a frog, a frog!", &[2..6, 10..14])
+        );
+    }
+
+    #[test]
+    fn only_matching_prints_each_match() {
+        let printer = Printer::new(Vec::new(), true, 1, 1).only_matching(true);
+
+        assert_eq!(
+            "poem.txt:3:frog\npoem.txt:3:frog\n",
+            print_one(printer, "a frog, a frog!", &[2..6, 10..14])
+        );
+    }
+
+    #[test]
+    fn json_messages_describe_each_match() {
+        let printer = Printer::new(Vec::new(), true, 0, 0).json(true).color(true);
+        let printed = print_one(printer, "a frog, a frog!", &[2..6, 10..14]);
+
+        let messages: Vec<serde_json::Value> = printed.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(json!({ "type": "begin", "data": { "path": "poem.txt" } }), messages[0]);
+        assert_eq!(
+            json!({
+                "type": "match",
+                "data": {
+                    "path": "poem.txt",
+                    "line_number": 3,
+                    "absolute_offset": 40,
+                    "text": "a frog, a frog!",
+                    "submatches": [
+                        { "match": "frog", "start": 2, "end": 6, "pattern": 0 },
+                        { "match": "frog", "start": 10, "end": 14, "pattern": 0 },
+                    ],
+                },
+            }),
+            messages[1]
+        );
+        assert_eq!("end", messages[2]["type"]);
+        assert_eq!(json!({ "matched_lines": 1, "matches": 2 }), messages[2]["data"]["stats"]);
+        assert_eq!("summary", messages[3]["type"]);
+        assert_eq!(1, messages[3]["data"]["stats"]["searches_with_match"]);
+    }
+
+    #[test]
+    fn json_offsets_are_into_the_bytes_read() {
+        // each of "\xe9", "\xff" and "\xfe" became a 3 byte U+FFFD in the text
+        let bytes = b"caf\xe9 au lait \xff\xfe lait";
+        let text = String::from_utf8_lossy(bytes);
+        let matches: Vec<Submatch> = text.match_indices("lait")
+            .map(|(start, _)| Submatch { range: start..start + 4, pattern: 0 })
+            .collect();
+
+        let mut printer = Printer::new(Vec::new(), true, 0, 0).json(true);
+        printer.start_file("cafe.txt".to_string()).unwrap();
+        printer.line(&Line { number: 1, offset: 0, text: &text, bytes, matches: &matches, matched: true }).unwrap();
+
+        let printed = String::from_utf8(printer.into_inner()).unwrap();
+        let message: serde_json::Value = serde_json::from_str(printed.lines().nth(1).unwrap()).unwrap();
+        let data = &message["data"];
+
+        assert_eq!(json!(BASE64.encode(bytes)), data["bytes"]);
+        assert_eq!(json!([8, 12]), json!([data["submatches"][0]["start"], data["submatches"][0]["end"]]));
+        assert_eq!(json!([16, 20]), json!([data["submatches"][1]["start"], data["submatches"][1]["end"]]));
+        assert_eq!(&bytes[16..20], b"lait");
+        assert_eq!("lait", data["submatches"][1]["match"]);
+
+        // valid lines don't need the bytes spelled out
+        let printer = Printer::new(Vec::new(), true, 0, 0).json(true);
+        assert!(!print_one(printer, "a frog, a frog!", &[2..6, 10..14]).contains("\"bytes\""));
+    }
+}
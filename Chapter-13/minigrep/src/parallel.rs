@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// Run `work` on every item with a pool of `jobs` threads, and hand the
+/// results to `done` in the same order as `items`. Each result is handed over
+/// as soon as it and all the ones before it are ready, so output can start
+/// before the slowest file is finished.
+///
+/// If `done` fails we stop handing out work and return its error.
+pub fn ordered_map<T, R, E, W, D>(items: &[T], jobs: usize, work: W, mut done: D) -> Result<(), E>
+where
+    T: Sync,
+    R: Send,
+    W: Fn(&T) -> R + Sync,
+    D: FnMut(R) -> Result<(), E>,
+{
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(items.len()) {
+            let sender = sender.clone();
+            let (next, work) = (&next, &work);
+
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+
+                let item = match items.get(i) {
+                    Some(item) => item,
+                    None => break,
+                };
+
+                // the receiver is only gone if `done` failed, so there's no
+                // point carrying on
+                if sender.send((i, work(item))).is_err() {
+                    break;
+                }
+            });
+        }
+
+        // only the workers hold senders now, so the loop below ends when
+        // they've all finished
+        drop(sender);
+
+        // results that turned up before the ones ahead of them
+        let mut waiting = HashMap::new();
+        let mut wanted = 0;
+
+        for (i, result) in receiver {
+            waiting.insert(i, result);
+
+            while let Some(result) = waiting.remove(&wanted) {
+                done(result)?;
+                wanted += 1;
+            }
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn results_come_back_in_order() {
+        let items: Vec<u64> = (0..20).collect();
+        let mut seen = Vec::new();
+
+        // make the early items the slow ones so they finish last
+        let result: Result<(), ()> = ordered_map(&items, 4, |&n| {
+            thread::sleep(Duration::from_millis(20 - n));
+            n * 10
+        }, |result| {
+            seen.push(result);
+            Ok(())
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(items.iter().map(|n| n * 10).collect::<Vec<_>>(), seen);
+    }
+
+    #[test]
+    fn stops_when_done_fails() {
+        let items: Vec<u32> = (0..200).collect();
+        let worked = AtomicUsize::new(0);
+
+        let result = ordered_map(&items, 2, |&n| {
+            worked.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(2));
+            n
+        }, |n| if n == 3 { Err("full") } else { Ok(()) });
+
+        assert_eq!(Err("full"), result);
+        assert!(worked.load(Ordering::SeqCst) < items.len());
+    }
+}
@@ -0,0 +1,263 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::matcher::{Kind, Matcher, Submatch};
+
+// Replace every match in `line` with `replacement`, giving back the new line
+// and where the replacements ended up in it.
+//
+// Like sed, `replacement` can refer to what matched: `$0` is the whole match,
+// and in regex mode `$1`, `$2`, ... and `$name` (or `${1}` and `${name}`) are
+// the query's capture groups. `$$` is a plain dollar sign.
+pub fn replace_all(matcher: &Matcher, line: &str, replacement: &str) -> (String, Vec<Submatch>) {
+    let mut replaced = String::with_capacity(line.len());
+    let mut ranges = Vec::new();
+    let mut end = 0;
+
+    match &matcher.kind {
+        Kind::Fixed { patterns, .. } => {
+            for m in patterns.find_iter(line) {
+                let whole = &line[m.range()];
+                let group = |name: &str| if name == "0" { Some(whole) } else { None };
+
+                replaced.push_str(&line[end..m.start()]);
+                let from = replaced.len();
+                expand(replacement, &group, &mut replaced);
+
+                ranges.push(Submatch { range: from..replaced.len(), pattern: m.pattern().as_usize() });
+                end = m.end();
+            }
+        },
+        Kind::Regex { regex, groups } => {
+            for captures in regex.captures_iter(line) {
+                let whole = captures.get(0).unwrap();
+                let pattern = groups.iter().position(|&group| captures.get(group).is_some()).unwrap_or(0);
+
+                let group = |name: &str| match name.parse::<usize>() {
+                    Ok(0) => Some(whole.as_str()),
+                    // every query is wrapped in a group of its own, so its
+                    // groups are numbered from there
+                    Ok(n) => captures.get(groups.get(pattern)? + n).map(|m| m.as_str()),
+                    Err(_) => captures.name(name).map(|m| m.as_str()),
+                };
+
+                replaced.push_str(&line[end..whole.start()]);
+                let from = replaced.len();
+                expand(replacement, &group, &mut replaced);
+
+                ranges.push(Submatch { range: from..replaced.len(), pattern });
+                end = whole.end();
+            }
+        },
+    }
+
+    replaced.push_str(&line[end..]);
+
+    (replaced, ranges)
+}
+
+// Append `replacement` to `out`, swapping each `$group` for what `group`
+// says it matched. Groups that didn't match become nothing.
+fn expand<'a>(replacement: &str, group: &dyn Fn(&str) -> Option<&'a str>, out: &mut String) {
+    let mut rest = replacement;
+
+    while let Some(dollar) = rest.find('$') {
+        out.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+
+        // ${name} can be followed by anything; a bare $name takes as many
+        // name characters as it can
+        let (name, after) = if let Some(braced) = rest.strip_prefix('{') {
+            match braced.find('}') {
+                Some(close) => (&braced[..close], &braced[close + 1..]),
+                None => ("", rest),
+            }
+        } else {
+            let len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            (&rest[..len], &rest[len..])
+        };
+
+        if name.is_empty() {
+            // not a reference after all, so keep the dollar sign
+            out.push('$');
+            continue;
+        }
+
+        out.push_str(group(name).unwrap_or(""));
+        rest = after;
+    }
+
+    out.push_str(rest);
+}
+
+// Whether `text` gets its matches replaced: whenever the pattern matches it
+// at all, even if only with an empty match like `^`. Printing and rewriting
+// both go by this, so --replace shows what --in-place would write.
+pub fn applies(matcher: &Matcher, text: &str) -> bool {
+    matcher.is_match(text)
+}
+
+// Rewrite the file at `path` with every match replaced, by writing a new copy
+// next to it and renaming that over the original, so anyone reading the file
+// sees either the old contents or the new, never half of each. With `backup`,
+// the original is kept alongside with that suffix.
+//
+// Lines that aren't valid UTF-8 are left exactly as they were, as are line
+// endings. Returns whether anything changed; if not, the file isn't touched.
+pub fn rewrite(path: &Path, matcher: &Matcher, replacement: &str, backup: Option<&str>) -> io::Result<bool> {
+    let original = File::open(path)?;
+    let permissions = original.metadata()?.permissions();
+    let mut reader = BufReader::new(original);
+
+    let temp = temp_path(path);
+    let mut writer = BufWriter::new(File::create(&temp)?);
+
+    let result = (|| {
+        let mut changed = false;
+        let mut buf = Vec::new();
+
+        loop {
+            buf.clear();
+
+            if reader.read_until(b'\n', &mut buf)? == 0 {
+                break;
+            }
+
+            let ending = line_ending(&buf);
+            let text = &buf[..buf.len() - ending.len()];
+
+            match std::str::from_utf8(text) {
+                Ok(text) if applies(matcher, text) => {
+                    let (replaced, _) = replace_all(matcher, text, replacement);
+                    changed |= replaced != text;
+
+                    writer.write_all(replaced.as_bytes())?;
+                    writer.write_all(ending)?;
+                },
+                _ => writer.write_all(&buf)?,
+            }
+        }
+
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        Ok(changed)
+    })();
+
+    match result {
+        Ok(true) => {
+            fs::set_permissions(&temp, permissions)?;
+
+            if let Some(suffix) = backup {
+                let mut backup = path.as_os_str().to_owned();
+                backup.push(suffix);
+                fs::copy(path, backup)?;
+            }
+
+            fs::rename(&temp, path)?;
+            Ok(true)
+        },
+        other => {
+            let _ = fs::remove_file(&temp);
+            other
+        },
+    }
+}
+
+// The line ending on the end of `line`, if it has one
+fn line_ending(line: &[u8]) -> &[u8] {
+    if line.ends_with(b"\r\n") {
+        &line[line.len() - 2..]
+    } else if line.ends_with(b"\n") {
+        &line[line.len() - 1..]
+    } else {
+        &[]
+    }
+}
+
+// Somewhere to write the new copy of `path`. It has to be in the same
+// directory, since renames don't work across filesystems, and a different
+// name every time, since -j could be rewriting the same file twice at once.
+fn temp_path(path: &Path) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+
+    path.with_file_name(format!(".{}.minigrep-{}-{}.tmp", name, std::process::id(), n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    fn matcher(args: &[&str]) -> Matcher {
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+
+        Matcher::new(&Config::parse(None, args, false).unwrap()).unwrap()
+    }
+
+    fn replace(args: &[&str], line: &str, replacement: &str) -> String {
+        replace_all(&matcher(args), line, replacement).0
+    }
+
+    #[test]
+    fn fixed_strings_are_replaced() {
+        let (line, ranges) = replace_all(&matcher(&["frog"]), "a frog, a frog!", "[$0]");
+
+        assert_eq!("a [frog], a [frog]!", line);
+        assert_eq!(vec![2..8, 12..18], ranges.into_iter().map(|r| r.range).collect::<Vec<_>>());
+
+        assert_eq!("a toad$", replace(&["frog"], "a frog", "toad$$"));
+        assert_eq!("a $ toad", replace(&["frog"], "a frog", "$ toad"));
+    }
+
+    #[test]
+    fn capture_groups_belong_to_each_query() {
+        let args = &["-E", "-e", r"(\w+)@(\w+)", "-e", r"(?P<day>\d+)/(\d+)"];
+
+        assert_eq!("bob at home", replace(args, "home@bob", "$2 at $1"));
+        // $2 here is the second query's second group, not the first query's
+        assert_eq!("12 of 3", replace(args, "3/12", "$2 of ${day}"));
+        assert_eq!("x", replace(args, "3/12", "x$9"));
+    }
+
+    #[test]
+    fn rewrite_is_in_place_with_a_backup() {
+        let dir = std::env::temp_dir().join(format!("minigrep_rewrite_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("poem.txt");
+
+        fs::write(&path, b"a frog\r\nno match\ncaf\xe9 frog\nfrog").unwrap();
+
+        assert!(rewrite(&path, &matcher(&["frog"]), "toad", Some(".bak")).unwrap());
+        assert_eq!(&b"a toad\r\nno match\ncaf\xe9 frog\ntoad"[..], &fs::read(&path).unwrap()[..]);
+        assert_eq!(&b"a frog\r\nno match\ncaf\xe9 frog\nfrog"[..], &fs::read(dir.join("poem.txt.bak")).unwrap()[..]);
+
+        // nothing to replace, so nothing changes and nothing is left behind
+        assert!(!rewrite(&path, &matcher(&["newt"]), "toad", None).unwrap());
+        assert_eq!(2, fs::read_dir(&dir).unwrap().count());
+
+        // an empty match still counts, same as when printing
+        assert!(rewrite(&path, &matcher(&["-E", "^"]), "> ", None).unwrap());
+        assert_eq!(&b"> a toad\r\n> no match\ncaf\xe9 frog\n> toad"[..], &fs::read(&path).unwrap()[..]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn temp_paths_are_never_reused() {
+        let path = Path::new("poem.txt");
+
+        assert_ne!(temp_path(path), temp_path(path));
+    }
+}
@@ -0,0 +1,384 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::matcher::{Matcher, Submatch};
+use crate::mmap;
+
+/// A line of input and where we found it, without its line ending.
+#[derive(Debug)]
+pub struct Line<'a> {
+    /// The 1-based line number.
+    pub number: usize,
+    /// The byte offset of the start of the line from the start of the input.
+    pub offset: u64,
+    /// The line as text, with any invalid UTF-8 replaced by U+FFFD.
+    pub text: &'a str,
+    /// The line as it was read, which `text` only differs from where invalid
+    /// UTF-8 was replaced.
+    pub bytes: &'a [u8],
+    /// The parts of `text` that matched. Empty for lines that didn't match,
+    /// which with `invert_match` is every selected line.
+    pub matches: &'a [Submatch],
+    /// Whether the search selected this line (so with `invert_match`,
+    /// whether it *didn't* match).
+    pub matched: bool,
+}
+
+/// Where a [`Searcher`] reports the lines it finds.
+///
+/// Each method returns whether to keep searching, so a sink can stop a
+/// search early by returning `Ok(false)`. An error stops it too, and is
+/// handed back by the search.
+pub trait Sink {
+    /// A line the search selected.
+    fn matched(&mut self, line: &Line) -> io::Result<bool>;
+
+    /// A line it didn't. Only called when the searcher was asked for
+    /// context.
+    fn context(&mut self, _line: &Line) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+/// A closure can be a sink too, and hears about both kinds of line, which it
+/// can tell apart by `line.matched`.
+impl<F: FnMut(&Line) -> io::Result<bool>> Sink for F {
+    fn matched(&mut self, line: &Line) -> io::Result<bool> {
+        self(line)
+    }
+
+    fn context(&mut self, line: &Line) -> io::Result<bool> {
+        self(line)
+    }
+}
+
+/// Searches input for lines a [`Matcher`] matches, telling a [`Sink`] about
+/// each one.
+///
+/// How it searches is up to it: a file can be searched whole from memory
+/// when only the matching lines matter.
+///
+/// ```
+/// use minigrep::{Line, Matcher, Searcher};
+///
+/// let matcher = Matcher::fixed(&["frog"]).unwrap();
+/// let mut found = Vec::new();
+///
+/// Searcher::new().search_slice(&matcher, b"I'm nobody!\nHow public, like a frog\n", &mut |line: &Line| {
+///     found.push(line.number);
+///     Ok(true)
+/// }).unwrap();
+///
+/// assert_eq!(vec![2], found);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Searcher {
+    invert_match: bool,
+    context: bool,
+}
+
+impl Searcher {
+    /// A searcher that selects the lines that match, without context.
+    pub fn new() -> Searcher {
+        Searcher::default()
+    }
+
+    /// Select the lines that don't match instead.
+    pub fn invert_match(mut self, invert_match: bool) -> Searcher {
+        self.invert_match = invert_match;
+        self
+    }
+
+    /// Tell the sink about the lines that weren't selected too, so it can
+    /// print them around the ones that were.
+    pub fn context(mut self, context: bool) -> Searcher {
+        self.context = context;
+        self
+    }
+
+    /// Search `reader` a line at a time. Only one line is held in memory at
+    /// once, and invalid UTF-8 is replaced rather than failing the whole
+    /// search.
+    pub fn search_reader<R, S>(&self, matcher: &Matcher, reader: R, sink: &mut S) -> io::Result<()>
+    where
+        R: Read,
+        S: Sink + ?Sized,
+    {
+        self.search_buffered(matcher, BufReader::new(reader), sink)
+    }
+
+    /// Search `contents` all at once if we can, or a line at a time if not.
+    pub fn search_slice<S: Sink + ?Sized>(&self, matcher: &Matcher, contents: &[u8], sink: &mut S) -> io::Result<()> {
+        match self.finder(matcher) {
+            Some(finder) => mmap::search(matcher, &finder, contents, sink),
+            None => self.search_buffered(matcher, contents, sink),
+        }
+    }
+
+    /// Search the file at `path`, memory mapping it if it can be searched
+    /// all at once.
+    pub fn search_path<S: Sink + ?Sized>(&self, matcher: &Matcher, path: &Path, sink: &mut S) -> io::Result<()> {
+        let file = File::open(path)?;
+
+        match self.map(matcher, &file) {
+            Some(contents) => self.search_slice(matcher, &contents, sink),
+            None => self.search_reader(matcher, file, sink),
+        }
+    }
+
+    // Map `file` into memory if `search_slice` would search it all at once
+    // and it can be mapped: empty files can't, and pipes and the like can't
+    // at all. Otherwise give back None, and it can be read a line at a time
+    // instead.
+    pub(crate) fn map(&self, matcher: &Matcher, file: &File) -> Option<Mmap> {
+        let metadata = file.metadata().ok()?;
+
+        if self.finder(matcher).is_some() && metadata.is_file() && metadata.len() > 0 {
+            mmap::map(file).ok()
+        } else {
+            None
+        }
+    }
+
+    // Search `reader` a line at a time, without buffering it again if it's
+    // already buffered
+    pub(crate) fn search_buffered<R, S>(&self, matcher: &Matcher, reader: R, sink: &mut S) -> io::Result<()>
+    where
+        R: BufRead,
+        S: Sink + ?Sized,
+    {
+        let mut submatches = Vec::new();
+
+        read_lines(reader, |number, offset, bytes, text| {
+            let is_match = matcher.is_match(text);
+
+            // only bother finding every match on lines that have one
+            submatches.clear();
+            if is_match {
+                matcher.find_all(text, &mut submatches);
+            }
+
+            let line = Line {
+                number,
+                offset,
+                text,
+                bytes,
+                matches: &submatches,
+                matched: is_match != self.invert_match,
+            };
+
+            if line.matched {
+                sink.matched(&line)
+            } else if self.context {
+                sink.context(&line)
+            } else {
+                Ok(true)
+            }
+        })
+    }
+
+    // The whole file search only finds matching lines, so it's no good for
+    // -v or context
+    fn finder<'a>(&self, matcher: &'a Matcher) -> Option<mmap::Finder<'a>> {
+        if self.invert_match || self.context {
+            None
+        } else {
+            mmap::Finder::new(matcher)
+        }
+    }
+}
+
+/// Read `reader` a line at a time, calling `each` with every line's number,
+/// offset, bytes and text, without its line ending. `each` returns whether to
+/// keep reading.
+pub(crate) fn read_lines<R, F>(mut reader: R, mut each: F) -> io::Result<()>
+where
+    R: BufRead,
+    F: FnMut(usize, u64, &[u8], &str) -> io::Result<bool>,
+{
+    let mut buf = Vec::new();
+    let mut number = 0;
+    let mut offset = 0;
+
+    loop {
+        buf.clear();
+
+        let len = reader.read_until(b'\n', &mut buf)?;
+
+        if len == 0 {
+            return Ok(());
+        }
+
+        number += 1;
+        let line_offset = offset;
+        offset += len as u64;
+
+        // same line endings as str::lines
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+
+            if buf.last() == Some(&b'\r') {
+                buf.pop();
+            }
+        }
+
+        if !each(number, line_offset, &buf, &String::from_utf8_lossy(&buf))? {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    fn matcher(args: &[&str]) -> Matcher {
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+
+        Matcher::new(&Config::parse(None, args, false).unwrap()).unwrap()
+    }
+
+    fn results(matcher: &Matcher, invert: bool, contents: &[u8]) -> Vec<(usize, String)> {
+        let mut results = Vec::new();
+
+        let searcher = Searcher::new().invert_match(invert);
+
+        searcher.search_reader(matcher, contents, &mut |line: &Line| {
+            results.push((line.number, line.text.to_string()));
+            Ok(true)
+        }).unwrap();
+
+        results
+    }
+
+    fn lines(expected: &[(usize, &str)]) -> Vec<(usize, String)> {
+        expected.iter().map(|&(number, line)| (number, line.to_string())).collect()
+    }
+
+    #[test]
+    fn one_result() {
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Duct tape.";
+
+        assert_eq!(
+            lines(&[(2, "safe, fast, productive.")]),
+            results(&matcher(&["duct"]), false, contents.as_bytes())
+        );
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Trust me.";
+
+        assert_eq!(
+            lines(&[(1, "Rust:"), (4, "Trust me.")]),
+            results(&matcher(&["-i", "rUsT"]), false, contents.as_bytes())
+        );
+    }
+
+    #[test]
+    fn regex_results() {
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Duct tape.";
+
+        assert_eq!(
+            lines(&[(3, "Pick three."), (4, "Duct tape.")]),
+            results(&matcher(&["-E", r"^[A-Z]\w+ \w+\.$"]), false, contents.as_bytes())
+        );
+    }
+
+    #[test]
+    fn invert_match() {
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Trust me.";
+
+        assert_eq!(
+            lines(&[(3, "Pick three.")]),
+            results(&matcher(&["st"]), true, contents.as_bytes())
+        );
+    }
+
+    #[test]
+    fn lines_know_their_offsets() {
+        let mut seen = Vec::new();
+
+        let searcher = Searcher::new().context(true);
+
+        searcher.search_reader(&matcher(&["x"]), &b"ab\r\nc\n\nxyz"[..], &mut |line: &Line| {
+            seen.push((line.number, line.offset, line.text.to_string(), line.matched));
+            Ok(true)
+        }).unwrap();
+
+        assert_eq!(
+            vec![
+                (1, 0, "ab".to_string(), false),
+                (2, 4, "c".to_string(), false),
+                (3, 6, "".to_string(), false),
+                (4, 7, "xyz".to_string(), true),
+            ],
+            seen
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_and_crlf_are_tolerated() {
+        let contents = b"caf\xe9 au lait\r\nplain tea\r\n";
+
+        assert_eq!(
+            lines(&[(1, "caf\u{FFFD} au lait")]),
+            results(&matcher(&["lait"]), false, &contents[..])
+        );
+    }
+
+    // A sink that isn't a closure, keeping count
+    #[derive(Default)]
+    struct Counts {
+        matched: usize,
+        context: usize,
+    }
+
+    impl Sink for Counts {
+        fn matched(&mut self, _line: &Line) -> io::Result<bool> {
+            self.matched += 1;
+            Ok(true)
+        }
+
+        fn context(&mut self, _line: &Line) -> io::Result<bool> {
+            self.context += 1;
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn sinks_only_hear_about_context_if_asked() {
+        let matcher = Matcher::fixed(&["nobody"]).unwrap();
+
+        for &(context, expected) in &[(false, 0), (true, 7)] {
+            let mut counts = Counts::default();
+            let searcher = Searcher::new().context(context);
+
+            // poem.txt can be searched whole without context, but not with it
+            assert_eq!(!context, searcher.map(&matcher, &File::open("poem.txt").unwrap()).is_some());
+            searcher.search_path(&matcher, Path::new("poem.txt"), &mut counts).unwrap();
+
+            assert_eq!((2, expected), (counts.matched, counts.context));
+        }
+    }
+}
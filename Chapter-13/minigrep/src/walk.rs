@@ -0,0 +1,131 @@
+use std::path::{Path, PathBuf};
+
+use ignore::WalkBuilder;
+
+use crate::Config;
+
+// how much of a file we look at when deciding if it's binary, same as grep
+const BINARY_CHECK_LEN: usize = 8 * 1024;
+
+// Something to search. `walked` is true when we found it by walking a
+// directory rather than being handed it, in which case binary files are
+// skipped quietly.
+pub struct Input {
+    pub path: PathBuf,
+    pub walked: bool,
+}
+
+impl Input {
+    // - means standard input, like with most command line tools
+    pub fn is_stdin(&self) -> bool {
+        self.path.to_str() == Some("-")
+    }
+}
+
+// Turn the file operands into the list of files to search. Without -r that's
+// just the operands; with -r directories are walked, skipping hidden files,
+// anything .gitignore'd and anything --include doesn't match. Problems
+// walking (like unreadable directories) are reported and skipped.
+pub fn inputs(config: &Config) -> Vec<Input> {
+    let mut inputs = Vec::new();
+
+    for filename in &config.filenames {
+        let path = Path::new(filename);
+
+        if !config.recursive || !path.is_dir() {
+            inputs.push(Input { path: path.to_path_buf(), walked: false });
+            continue;
+        }
+
+        let walker = WalkBuilder::new(path)
+            // respect .gitignore files even outside of a git repository
+            .require_git(false)
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build();
+
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    eprintln!("minigrep: {}", e);
+                    continue;
+                },
+            };
+
+            if !entry.file_type().is_some_and(|kind| kind.is_file()) {
+                continue;
+            }
+
+            if let Some(include) = &config.include {
+                if !include.is_match(entry.file_name()) {
+                    continue;
+                }
+            }
+
+            // walking . gives us ./src/lib.rs, but src/lib.rs reads better
+            let path = entry.path();
+            let path = path.strip_prefix(".").unwrap_or(path);
+
+            inputs.push(Input { path: path.to_path_buf(), walked: true });
+        }
+    }
+
+    inputs
+}
+
+// A NUL byte near the start of a file is a good sign it isn't text.
+pub fn is_binary(contents: &[u8]) -> bool {
+    let start = &contents[..contents.len().min(BINARY_CHECK_LEN)];
+
+    start.contains(&0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn config(args: &[&str]) -> Config {
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+
+        Config::parse(None, args, false).unwrap()
+    }
+
+    #[test]
+    fn walks_respecting_gitignore_hidden_and_include() {
+        let dir = std::env::temp_dir().join(format!("minigrep_walk_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::create_dir_all(dir.join(".hidden")).unwrap();
+        fs::write(dir.join(".gitignore"), "target/\n").unwrap();
+        fs::write(dir.join("src/lib.rs"), "fn main() {}").unwrap();
+        fs::write(dir.join("src/notes.txt"), "fn").unwrap();
+        fs::write(dir.join("target/out.rs"), "fn").unwrap();
+        fs::write(dir.join(".hidden/secret.rs"), "fn").unwrap();
+
+        let root = dir.to_str().unwrap();
+        let found: Vec<PathBuf> = inputs(&config(&["-r", "--include", "*.rs", "fn", root]))
+            .into_iter()
+            .map(|input| input.path)
+            .collect();
+
+        assert_eq!(vec![dir.join("src/lib.rs")], found);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn operands_pass_through_without_recursion() {
+        let found = inputs(&config(&["fn", "src", "poem.txt"]));
+
+        assert_eq!(2, found.len());
+        assert!(found.iter().all(|input| !input.walked));
+    }
+
+    #[test]
+    fn nul_bytes_mean_binary() {
+        assert!(is_binary(b"\x7fELF\x02\x01\x01\x00"));
+        assert!(!is_binary("plain old text".as_bytes()));
+    }
+}
@@ -1,46 +1,165 @@
-use std::fs;
+use std::env;
 use std::io::prelude::*;
-use std::net::TcpListener;
+use std::io::BufReader;
 use std::net::TcpStream;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
-use hello_webserver::ThreadPool;
+use serde_json::json;
+use hello_webserver::body::{BodyError, DEFAULT_BODY_LIMIT};
+use hello_webserver::http::Request;
+use hello_webserver::proxy::Proxy;
+use hello_webserver::server::Server;
+use hello_webserver::template::Templates;
+use hello_webserver::websocket::{self, Message, WebSocket};
 
-fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 512];
-    stream.read(&mut buffer).unwrap();
+/// Everything the handlers share between workers.
+struct Site {
+    templates: Templates,
+    proxy: Option<Arc<Proxy>>,
+}
+
+fn handle_connection(stream: TcpStream, site: &Site) {
+    let peer = stream.peer_addr().ok().map(|addr| addr.ip());
+    let mut reader = BufReader::new(stream);
+
+    let mut request = match Request::read_from(&mut reader) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("Bad request: {}", e);
+            return;
+        },
+    };
 
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
+    if let Some(proxy) = site.proxy.as_ref().filter(|proxy| proxy.matches(&request)) {
+        if let Err(e) = proxy.handle(&request, &mut reader, peer) {
+            eprintln!("Proxy error: {}", e);
+        }
+        return;
+    }
+
+    if request.path == "/ws" {
+        match websocket::accept(reader, &request) {
+            Ok(socket) => handle_websocket(socket),
+            Err(e) => eprintln!("WebSocket handshake failed: {}", e),
+        }
+        return;
+    }
 
-    let (status_line, filename) = if buffer.starts_with(get) {
+    if request.method == "POST" && request.path == "/echo" {
+        let response = match echo_body(&mut request, &mut reader) {
+            Ok(text) => format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+                text.len(),
+                text,
+            ),
+            Err(e) => e.response(),
+        };
+
+        let stream = reader.get_mut();
+        stream.write_all(response.as_bytes()).unwrap();
+        stream.flush().unwrap();
+        return;
+    }
+
+    let name = request.query().into_iter()
+        .find(|(key, _)| key == "name")
+        .map(|(_, value)| value);
+
+    let (status_line, filename) = if request.method == "GET" && request.route() == "/" {
         ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else if buffer.starts_with(sleep) {
+    } else if request.method == "GET" && request.route() == "/sleep" {
         thread::sleep(Duration::from_secs(5));
         ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
     } else {
         ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
     };
 
-    let contents = fs::read_to_string(filename).unwrap();
+    let context = json!({
+        "title": "Hello World!",
+        "name": name,
+        "path": request.route(),
+    });
 
-    let response = format!("{}{}", status_line, contents);
+    let response = match site.templates.render(filename, &context) {
+        Ok(contents) => format!("{}{}", status_line, contents),
+        Err(e) => {
+            eprintln!("Template error: {}", e);
+            "HTTP/1.1 500 Internal Server Error\r\n\r\n".to_string()
+        },
+    };
 
-    stream.write(response.as_bytes()).unwrap();
+    let stream = reader.get_mut();
+    stream.write_all(response.as_bytes()).unwrap();
     stream.flush().unwrap();
 }
 
-fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
+/// Decode whatever kind of body was posted and describe it in plain text.
+fn echo_body(request: &mut Request, reader: &mut BufReader<TcpStream>) -> Result<String, BodyError> {
+    request.read_body(reader, DEFAULT_BODY_LIMIT)?;
 
-    for stream in listener.incoming().take(4) {
-        let stream = stream.unwrap();
+    let content_type = request.header("Content-Type").unwrap_or("").to_ascii_lowercase();
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+    if content_type.starts_with("application/json") {
+        let value: serde_json::Value = request.json()?;
+        Ok(format!("{:#}\n", value))
+    } else if content_type.starts_with("multipart/form-data") {
+        let parts = request.multipart()?;
+        Ok(parts.iter()
+            .map(|part| format!("{}: {} bytes\n", part.name, part.data.len()))
+            .collect())
+    } else {
+        let fields = request.form()?;
+        Ok(fields.iter()
+            .map(|(name, value)| format!("{} = {}\n", name, value))
+            .collect())
+    }
+}
+
+/// Echo every text and binary message back to the client until it hangs up.
+fn handle_websocket(mut socket: WebSocket<TcpStream>) {
+    while let Some(message) = socket.next() {
+        let result = match message {
+            Ok(Message::Text(text)) => socket.send(Message::Text(text)),
+            Ok(Message::Binary(data)) => socket.send(Message::Binary(data)),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            eprintln!("WebSocket error: {}", e);
+            break;
+        }
     }
+}
+
+fn main() {
+    let server = Server::builder()
+        .workers(4)
+        .max_connections(64)
+        .rate_limit(5.0, 20)
+        .shutdown_after(4)
+        .bind("127.0.0.1:7878")
+        .unwrap();
+
+    let mut templates = Templates::load("templates").unwrap_or_else(|e| {
+        panic!("Couldn't load templates: {}", e);
+    });
+
+    // HELLO_DEV=1 re-reads templates that change on disk while we run.
+    templates.set_hot_reload(env::var("HELLO_DEV").is_ok());
+
+    // HELLO_UPSTREAMS=127.0.0.1:8001,127.0.0.1:8002 proxies /api to them.
+    let proxy = env::var("HELLO_UPSTREAMS").ok().map(|upstreams| {
+        let upstreams: Vec<&str> = upstreams.split(',').map(str::trim).collect();
+        let proxy = Arc::new(Proxy::new("/api", &upstreams));
+        Proxy::spawn_health_checks(&proxy, Duration::from_secs(5));
+        proxy
+    });
+
+    let site = Arc::new(Site { templates, proxy });
 
-    println!("Shutting down.");
+    server.run(move |stream| {
+        handle_connection(stream, &site);
+    });
 }
@@ -0,0 +1,346 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Bodies larger than this are refused unless a handler asks for more.
+pub const DEFAULT_BODY_LIMIT: usize = 1024 * 1024;
+
+/// Everything that can go wrong reading or decoding a request body.
+///
+/// Each variant maps onto the HTTP status a handler should answer with, see
+/// `status_line`.
+#[derive(Debug)]
+pub enum BodyError {
+    /// The body is bigger than the limit the handler allowed.
+    TooLarge { limit: usize },
+    /// The client sent a body without telling us how long it is.
+    LengthRequired,
+    /// The body isn't in the format the handler asked for.
+    UnsupportedMediaType(String),
+    /// The body claimed to be in the right format but couldn't be decoded.
+    Malformed(String),
+    Io(io::Error),
+}
+
+impl BodyError {
+    /// The status line (including the trailing CRLF) to send back for this
+    /// error.
+    pub fn status_line(&self) -> &'static str {
+        match self {
+            BodyError::TooLarge { .. } => "HTTP/1.1 413 Payload Too Large\r\n",
+            BodyError::LengthRequired => "HTTP/1.1 411 Length Required\r\n",
+            BodyError::UnsupportedMediaType(_) => "HTTP/1.1 415 Unsupported Media Type\r\n",
+            BodyError::Malformed(_) => "HTTP/1.1 400 Bad Request\r\n",
+            BodyError::Io(_) => "HTTP/1.1 500 Internal Server Error\r\n",
+        }
+    }
+
+    /// A complete response for this error with the message as a plain text
+    /// body. We always close the connection after an error since we may not
+    /// have drained the body.
+    pub fn response(&self) -> String {
+        let message = self.to_string();
+
+        format!(
+            "{}Connection: close\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            self.status_line(),
+            message.len(),
+            message,
+        )
+    }
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BodyError::TooLarge { limit } => write!(f, "request body is larger than {} bytes", limit),
+            BodyError::LengthRequired => write!(f, "request body needs a Content-Length"),
+            BodyError::UnsupportedMediaType(expected) => write!(f, "expected a {} body", expected),
+            BodyError::Malformed(reason) => write!(f, "malformed request body: {}", reason),
+            BodyError::Io(e) => write!(f, "couldn't read request body: {}", e),
+        }
+    }
+}
+
+impl Error for BodyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BodyError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BodyError {
+    fn from(e: io::Error) -> BodyError {
+        BodyError::Io(e)
+    }
+}
+
+/// One field of a `multipart/form-data` body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Part {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+impl Part {
+    /// The part's data as text, if it is valid UTF-8.
+    pub fn text(&self) -> Option<&str> {
+        std::str::from_utf8(&self.data).ok()
+    }
+}
+
+/// Split a header value like `multipart/form-data; boundary=abc` into its
+/// lowercased media type and its parameters. Quoted values can hold `;`
+/// and backslash-escaped quotes, as in `filename="a;\"b\".txt"`.
+pub fn parse_media_type(value: &str) -> (String, Vec<(String, String)>) {
+    let mut pieces = split_params(value).into_iter();
+    let media_type = pieces.next().unwrap_or("").trim().to_ascii_lowercase();
+
+    let params = pieces
+        .filter_map(|param| {
+            let eq = param.find('=')?;
+            let name = param[..eq].trim().to_ascii_lowercase();
+            let value = unquote(param[eq + 1..].trim());
+            Some((name, value))
+        })
+        .collect();
+
+    (media_type, params)
+}
+
+/// Split on the `;`s that aren't inside a quoted string.
+fn split_params(value: &str) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                pieces.push(&value[start..i]);
+                start = i + 1;
+            },
+            _ => (),
+        }
+    }
+
+    pieces.push(&value[start..]);
+    pieces
+}
+
+/// The contents of a quoted string, or `value` itself if it isn't one.
+fn unquote(value: &str) -> String {
+    let inner = match value.strip_prefix('"') {
+        Some(rest) => rest.strip_suffix('"').unwrap_or(rest),
+        None => return value.to_string(),
+    };
+
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+
+    unquoted
+}
+
+/// Decode an `application/x-www-form-urlencoded` body into name/value pairs,
+/// keeping the order (and any repeats) the client sent.
+pub fn parse_urlencoded(body: &[u8]) -> Result<Vec<(String, String)>, BodyError> {
+    body.split(|&byte| byte == b'&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut halves = pair.splitn(2, |&byte| byte == b'=');
+            let name = percent_decode(halves.next().unwrap_or(&[]))?;
+            let value = percent_decode(halves.next().unwrap_or(&[]))?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+fn percent_decode(encoded: &[u8]) -> Result<String, BodyError> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.iter();
+
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let high = bytes.next().and_then(|&b| (b as char).to_digit(16));
+                let low = bytes.next().and_then(|&b| (b as char).to_digit(16));
+
+                match (high, low) {
+                    (Some(high), Some(low)) => decoded.push((high * 16 + low) as u8),
+                    _ => return Err(BodyError::Malformed("bad percent escape".to_string())),
+                }
+            },
+            byte => decoded.push(byte),
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| BodyError::Malformed("form field is not valid UTF-8".to_string()))
+}
+
+/// Decode a `multipart/form-data` body separated by `boundary`.
+pub fn parse_multipart(body: &[u8], boundary: &str) -> Result<Vec<Part>, BodyError> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let malformed = |reason: &str| BodyError::Malformed(reason.to_string());
+
+    let mut rest = match find(body, &delimiter) {
+        Some(start) => &body[start + delimiter.len()..],
+        None => return Err(malformed("missing multipart boundary")),
+    };
+
+    let mut parts = Vec::new();
+
+    loop {
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+
+        rest = match rest.strip_prefix(b"\r\n") {
+            Some(rest) => rest,
+            None => return Err(malformed("boundary not followed by a line break")),
+        };
+
+        let head_end = match find(rest, b"\r\n\r\n") {
+            Some(end) => end,
+            None => return Err(malformed("part headers never end")),
+        };
+
+        let head = std::str::from_utf8(&rest[..head_end])
+            .map_err(|_| malformed("part headers are not valid UTF-8"))?;
+        rest = &rest[head_end + 4..];
+
+        let mut closing = b"\r\n".to_vec();
+        closing.extend_from_slice(&delimiter);
+
+        let data_end = match find(rest, &closing) {
+            Some(end) => end,
+            None => return Err(malformed("part is never closed")),
+        };
+
+        parts.push(parse_part(head, rest[..data_end].to_vec())?);
+        rest = &rest[data_end + closing.len()..];
+    }
+}
+
+fn parse_part(head: &str, data: Vec<u8>) -> Result<Part, BodyError> {
+    let mut name = None;
+    let mut filename = None;
+    let mut content_type = None;
+
+    for line in head.split("\r\n") {
+        let colon = match line.find(':') {
+            Some(colon) => colon,
+            None => continue,
+        };
+        let value = line[colon + 1..].trim();
+
+        match line[..colon].trim().to_ascii_lowercase().as_str() {
+            "content-disposition" => {
+                let (_, params) = parse_media_type(value);
+
+                for (key, value) in params {
+                    match key.as_str() {
+                        "name" => name = Some(value),
+                        "filename" => filename = Some(value),
+                        _ => (),
+                    }
+                }
+            },
+            "content-type" => content_type = Some(value.to_string()),
+            _ => (),
+        }
+    }
+
+    match name {
+        Some(name) => Ok(Part { name, filename, content_type, data }),
+        None => Err(BodyError::Malformed("part has no name".to_string())),
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urlencoded_decodes_escapes_and_plus() {
+        let fields = parse_urlencoded(b"name=Peter+Farr&lang=Rust%21&empty=").unwrap();
+
+        assert_eq!(
+            vec![
+                ("name".to_string(), "Peter Farr".to_string()),
+                ("lang".to_string(), "Rust!".to_string()),
+                ("empty".to_string(), "".to_string()),
+            ],
+            fields
+        );
+    }
+
+    #[test]
+    fn urlencoded_rejects_truncated_escape() {
+        assert!(parse_urlencoded(b"bad=%4").is_err());
+    }
+
+    #[test]
+    fn multipart_splits_fields_and_files() {
+        let body = b"--XyZ\r\n\
+Content-Disposition: form-data; name=\"title\"\r\n\
+\r\n\
+Hello\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+line one\r\nline two\r\n\
+--XyZ--\r\n";
+
+        let parts = parse_multipart(body, "XyZ").unwrap();
+
+        assert_eq!(2, parts.len());
+        assert_eq!("title", parts[0].name);
+        assert_eq!(Some("Hello"), parts[0].text());
+        assert_eq!(Some("a.txt".to_string()), parts[1].filename);
+        assert_eq!(Some("text/plain".to_string()), parts[1].content_type);
+        assert_eq!(b"line one\r\nline two".to_vec(), parts[1].data);
+    }
+
+    #[test]
+    fn media_type_params_are_unquoted() {
+        let (media_type, params) = parse_media_type("Multipart/Form-Data; boundary=\"abc\"");
+
+        assert_eq!("multipart/form-data", media_type);
+        assert_eq!(vec![("boundary".to_string(), "abc".to_string())], params);
+    }
+
+    #[test]
+    fn quoted_params_can_hold_semicolons() {
+        let (_, params) = parse_media_type(r#"form-data; name="file"; filename="a;\"b\".txt"; x=1"#);
+
+        assert_eq!(
+            vec![
+                ("name".to_string(), "file".to_string()),
+                ("filename".to_string(), "a;\"b\".txt".to_string()),
+                ("x".to_string(), "1".to_string()),
+            ],
+            params
+        );
+    }
+}
@@ -0,0 +1,304 @@
+use std::io::{self, BufRead, Read};
+
+use serde::de::DeserializeOwned;
+
+use crate::body::{self, BodyError, Part};
+
+/// The largest request head (request line plus headers) we are willing to
+/// buffer before giving up on a client.
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+/// A parsed HTTP/1.1 request head.
+///
+/// Only the request line and headers are read; anything after the blank line
+/// is left in the reader until a handler calls `read_body`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// Read a request line and headers from `reader`.
+    ///
+    /// Returns an `InvalidData` error if the head is malformed or longer than
+    /// `MAX_HEAD_LEN`, and `UnexpectedEof` if the client hangs up early.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> io::Result<Request> {
+        let mut read = 0;
+
+        let request_line = read_line(reader, &mut read)?;
+        let mut parts = request_line.split_whitespace();
+
+        let (method, path, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(path), Some(version)) => {
+                (method.to_string(), path.to_string(), version.to_string())
+            },
+            _ => return Err(invalid("malformed request line")),
+        };
+
+        let mut headers = Vec::new();
+
+        loop {
+            let line = read_line(reader, &mut read)?;
+
+            if line.is_empty() {
+                break;
+            }
+
+            match line.find(':') {
+                Some(colon) => {
+                    let name = line[..colon].trim().to_string();
+                    let value = line[colon + 1..].trim().to_string();
+                    headers.push((name, value));
+                },
+                None => return Err(invalid("malformed header line")),
+            }
+        }
+
+        Ok(Request { method, path, version, headers, body: Vec::new() })
+    }
+
+    /// How long the body that follows the head is, from `Content-Length`.
+    ///
+    /// Requests without a body (no `Content-Length` and no
+    /// `Transfer-Encoding`) have a length of 0. A request with both is
+    /// refused, since something in front of us could be using the other one
+    /// to find where the body ends (RFC 7230 section 3.3.3).
+    pub fn content_length(&self) -> Result<u64, BodyError> {
+        let chunked = self.header("Transfer-Encoding").is_some();
+
+        match self.header("Content-Length") {
+            Some(_) if chunked => Err(BodyError::Malformed("both Content-Length and Transfer-Encoding".to_string())),
+            // `parse` would also take a leading `+`
+            Some(len) if !len.is_empty() && len.bytes().all(|b| b.is_ascii_digit()) => {
+                len.parse().map_err(|_| BodyError::Malformed("bad Content-Length".to_string()))
+            },
+            Some(_) => Err(BodyError::Malformed("bad Content-Length".to_string())),
+            None if chunked => Err(BodyError::LengthRequired),
+            None => Ok(0),
+        }
+    }
+
+    /// Read the body that follows the head, refusing anything over `limit`
+    /// bytes.
+    ///
+    /// The length comes from `content_length`; a declared length over the
+    /// limit is rejected before a single byte is read.
+    pub fn read_body<R: BufRead>(&mut self, reader: &mut R, limit: usize) -> Result<&[u8], BodyError> {
+        let len = self.content_length()?;
+
+        if len > limit as u64 {
+            return Err(BodyError::TooLarge { limit });
+        }
+
+        let mut body = vec![0; len as usize];
+        reader.read_exact(&mut body)?;
+        self.body = body;
+
+        Ok(&self.body)
+    }
+
+    /// The body read by `read_body`, or an empty slice if it hasn't been
+    /// called.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Decode an `application/x-www-form-urlencoded` body.
+    pub fn form(&self) -> Result<Vec<(String, String)>, BodyError> {
+        self.expect_media_type("application/x-www-form-urlencoded")?;
+
+        body::parse_urlencoded(&self.body)
+    }
+
+    /// Decode a `multipart/form-data` body into its parts.
+    pub fn multipart(&self) -> Result<Vec<Part>, BodyError> {
+        let params = self.expect_media_type("multipart/form-data")?;
+
+        let boundary = params.into_iter()
+            .find(|(name, _)| name == "boundary")
+            .map(|(_, value)| value);
+
+        match boundary {
+            Some(boundary) => body::parse_multipart(&self.body, &boundary),
+            None => Err(BodyError::Malformed("multipart body without a boundary".to_string())),
+        }
+    }
+
+    /// Deserialize an `application/json` body into `T`.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, BodyError> {
+        self.expect_media_type("application/json")?;
+
+        serde_json::from_slice(&self.body).map_err(|e| BodyError::Malformed(e.to_string()))
+    }
+
+    fn expect_media_type(&self, expected: &str) -> Result<Vec<(String, String)>, BodyError> {
+        let (media_type, params) = body::parse_media_type(self.header("Content-Type").unwrap_or(""));
+
+        if media_type == expected {
+            Ok(params)
+        } else {
+            Err(BodyError::UnsupportedMediaType(expected.to_string()))
+        }
+    }
+
+    /// The path with any query string cut off.
+    pub fn route(&self) -> &str {
+        match self.path.find('?') {
+            Some(query) => &self.path[..query],
+            None => &self.path,
+        }
+    }
+
+    /// Decode the query string, ignoring it if it's malformed.
+    pub fn query(&self) -> Vec<(String, String)> {
+        match self.path.find('?') {
+            Some(query) => body::parse_urlencoded(&self.path.as_bytes()[query + 1..]).unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Look up a header by name, ignoring ASCII case.
+    ///
+    /// If the header was sent more than once the first value is returned.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Iterate over every header in the order the client sent them.
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Does a comma separated header such as `Connection` contain `token`?
+    pub fn header_contains(&self, name: &str, token: &str) -> bool {
+        self.headers.iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .flat_map(|(_, value)| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+}
+
+fn read_line<R: BufRead>(reader: &mut R, read: &mut usize) -> io::Result<String> {
+    let mut line = Vec::new();
+    let limit = (MAX_HEAD_LEN - *read) as u64;
+
+    let n = reader.by_ref().take(limit).read_until(b'\n', &mut line)?;
+    *read += n;
+
+    if n == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+    }
+
+    if line.last() != Some(&b'\n') {
+        return Err(invalid("request head too large"));
+    }
+
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    String::from_utf8(line).map_err(|_| invalid("request head is not valid UTF-8"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_request_line_and_headers() {
+        let mut raw: &[u8] = b"GET /ws HTTP/1.1\r\n\
+Host: 127.0.0.1:7878\r\n\
+Connection: keep-alive, Upgrade\r\n\
+\r\n\
+body";
+
+        let request = Request::read_from(&mut raw).unwrap();
+
+        assert_eq!("GET", request.method);
+        assert_eq!("/ws", request.path);
+        assert_eq!("HTTP/1.1", request.version);
+        assert_eq!(Some("127.0.0.1:7878"), request.header("host"));
+        assert!(request.header_contains("connection", "upgrade"));
+        assert_eq!(b"body", raw);
+    }
+
+    #[test]
+    fn splits_query_from_route() {
+        let mut raw: &[u8] = b"GET /?name=Ferris+the+crab HTTP/1.1\r\n\r\n";
+
+        let request = Request::read_from(&mut raw).unwrap();
+
+        assert_eq!("/", request.route());
+        assert_eq!(vec![("name".to_string(), "Ferris the crab".to_string())], request.query());
+    }
+
+    #[test]
+    fn rejects_oversized_head() {
+        let mut raw = b"GET / HTTP/1.1\r\nX-Junk: ".to_vec();
+        raw.extend(vec![b'a'; MAX_HEAD_LEN]);
+
+        let err = Request::read_from(&mut &raw[..]).unwrap_err();
+
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn reads_and_decodes_json_body() {
+        let mut raw: &[u8] = b"POST /echo HTTP/1.1\r\n\
+Content-Type: application/json; charset=utf-8\r\n\
+Content-Length: 13\r\n\
+\r\n\
+{\"answer\":42}";
+
+        let mut request = Request::read_from(&mut raw).unwrap();
+        request.read_body(&mut raw, 1024).unwrap();
+        let value: serde_json::Value = request.json().unwrap();
+
+        assert_eq!(42, value["answer"]);
+        assert!(request.form().is_err());
+    }
+
+    #[test]
+    fn ambiguous_lengths_are_bad_requests() {
+        let heads: [&[u8]; 3] = [
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\nhello",
+            b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello",
+            b"POST / HTTP/1.1\r\nContent-Length: -0\r\n\r\n",
+        ];
+
+        for &head in &heads {
+            let mut raw = head;
+            let mut request = Request::read_from(&mut raw).unwrap();
+
+            match request.read_body(&mut raw, 1024) {
+                Err(e @ BodyError::Malformed(_)) => assert_eq!("HTTP/1.1 400 Bad Request\r\n", e.status_line()),
+                other => panic!("expected Malformed, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_body_is_refused_before_reading() {
+        let mut raw: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 2048\r\n\r\n";
+
+        let mut request = Request::read_from(&mut raw).unwrap();
+
+        match request.read_body(&mut raw, 1024) {
+            Err(e @ BodyError::TooLarge { .. }) => {
+                assert_eq!("HTTP/1.1 413 Payload Too Large\r\n", e.status_line());
+            },
+            other => panic!("expected TooLarge, got {:?}", other),
+        }
+    }
+}
@@ -1,6 +1,17 @@
 use std::thread;
 use std::sync::{Arc, Mutex, mpsc};
 
+pub mod body;
+pub mod http;
+pub mod proxy;
+pub mod rate_limit;
+pub mod server;
+pub mod template;
+pub mod websocket;
+
+#[cfg(test)]
+mod testing;
+
 pub struct ThreadPool {
     workers: Vec<Worker>,
     tx: mpsc::Sender<Message>,
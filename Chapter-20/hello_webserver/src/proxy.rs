@@ -0,0 +1,416 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::http::Request;
+
+/// How long we wait to connect to an upstream before trying the next one.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long we wait on an upstream's response by default before giving up
+/// on it.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Headers that only make sense for a single hop, so they're never
+/// forwarded as-is.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+struct Upstream {
+    addr: String,
+    healthy: AtomicBool,
+}
+
+/// Forwards requests under a path prefix to one of several backends.
+///
+/// Backends are picked round-robin, skipping any that failed their last
+/// health check or refused the last connection. Bodies are streamed in both
+/// directions rather than buffered, and every request is sent upstream with
+/// `Connection: close` so the end of the response is simply the upstream
+/// hanging up.
+pub struct Proxy {
+    prefix: String,
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+    health_path: Option<String>,
+    timeout: Duration,
+}
+
+impl Proxy {
+    /// Create a proxy for routes starting with `prefix`.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if `upstreams` is empty.
+    pub fn new(prefix: &str, upstreams: &[&str]) -> Proxy {
+        assert!(!upstreams.is_empty());
+
+        let upstreams = upstreams.iter()
+            .map(|addr| Upstream { addr: addr.to_string(), healthy: AtomicBool::new(true) })
+            .collect();
+
+        Proxy {
+            prefix: prefix.to_string(),
+            upstreams,
+            next: AtomicUsize::new(0),
+            health_path: None,
+            timeout: UPSTREAM_TIMEOUT,
+        }
+    }
+
+    /// Have health checks `GET` this path and expect a 2xx, instead of only
+    /// checking that the upstream accepts connections.
+    pub fn with_health_path(mut self, path: &str) -> Proxy {
+        self.health_path = Some(path.to_string());
+        self
+    }
+
+    /// Give up on an upstream that goes quiet for `timeout` while we wait
+    /// for its response, instead of the default 30 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Proxy {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Should this request be proxied?
+    pub fn matches(&self, request: &Request) -> bool {
+        let route = request.route();
+
+        route.starts_with(&self.prefix)
+            && (self.prefix.ends_with('/') || route.len() == self.prefix.len() || route[self.prefix.len()..].starts_with('/'))
+    }
+
+    /// Forward `request` and stream the upstream's response back.
+    ///
+    /// `reader` is the client connection the request head was read from;
+    /// the body is copied from it straight to the upstream. `peer` is the
+    /// client's address for `X-Forwarded-For`. A body that
+    /// `Request::content_length` won't vouch for gets the same error
+    /// response `Request::read_body` would, without reaching an upstream. If
+    /// no upstream can be reached the client gets a `502 Bad Gateway`.
+    pub fn handle<S: Read + Write>(&self, request: &Request, reader: &mut BufReader<S>, peer: Option<IpAddr>) -> io::Result<()> {
+        // We only stream bodies we know the length of, and only when there's
+        // no doubt about what that is.
+        let body_len = match request.content_length() {
+            Ok(len) => len,
+            Err(e) => {
+                let client = reader.get_mut();
+                client.write_all(e.response().as_bytes())?;
+                return client.flush();
+            },
+        };
+
+        let (upstream, mut backend) = match self.connect() {
+            Some(connected) => connected,
+            None => return respond(reader.get_mut(), "502 Bad Gateway"),
+        };
+
+        // A backend that stops answering gives up the worker too.
+        if backend.set_read_timeout(Some(self.timeout)).is_err() {
+            return respond(reader.get_mut(), "502 Bad Gateway");
+        }
+
+        let forwarded = backend.try_clone().and_then(|mut backend| {
+            backend.write_all(forwarded_head(request, &upstream.addr, peer).as_bytes())?;
+
+            io::copy(&mut reader.by_ref().take(body_len), &mut backend)?;
+
+            backend.flush()
+        });
+
+        if forwarded.is_err() {
+            upstream.healthy.store(false, Ordering::SeqCst);
+            return respond(reader.get_mut(), "502 Bad Gateway");
+        }
+
+        let client = reader.get_mut();
+        io::copy(&mut backend, client)?;
+        client.flush()
+    }
+
+    /// Connect to the next healthy upstream, marking any that refuse us as
+    /// unhealthy along the way. When every upstream looks down we still try
+    /// them all once, in case they've recovered since the last check.
+    fn connect(&self) -> Option<(&Upstream, TcpStream)> {
+        let start = self.next.fetch_add(1, Ordering::SeqCst);
+        let count = self.upstreams.len();
+
+        let order = (0..count).map(|offset| &self.upstreams[(start + offset) % count]);
+        let (healthy, unhealthy): (Vec<_>, Vec<_>) = order.partition(|upstream| upstream.healthy.load(Ordering::SeqCst));
+
+        for upstream in healthy.into_iter().chain(unhealthy) {
+            match connect_timeout(&upstream.addr) {
+                Ok(stream) => {
+                    upstream.healthy.store(true, Ordering::SeqCst);
+                    return Some((upstream, stream));
+                },
+                Err(_) => upstream.healthy.store(false, Ordering::SeqCst),
+            }
+        }
+
+        None
+    }
+
+    /// Check every upstream once, updating which ones we'll send traffic to.
+    pub fn check_health(&self) {
+        for upstream in &self.upstreams {
+            let healthy = self.probe(&upstream.addr).unwrap_or(false);
+            upstream.healthy.store(healthy, Ordering::SeqCst);
+        }
+    }
+
+    fn probe(&self, addr: &str) -> io::Result<bool> {
+        let mut stream = connect_timeout(addr)?;
+
+        let path = match &self.health_path {
+            Some(path) => path,
+            None => return Ok(true),
+        };
+
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, addr)?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+
+        Ok(status_line.split_whitespace().nth(1).is_some_and(|status| status.starts_with('2')))
+    }
+
+    /// Is this upstream currently getting traffic?
+    pub fn is_healthy(&self, addr: &str) -> bool {
+        self.upstreams.iter()
+            .any(|upstream| upstream.addr == addr && upstream.healthy.load(Ordering::SeqCst))
+    }
+
+    /// Run `check_health` every `interval` on a background thread.
+    ///
+    /// The thread only holds a weak reference and exits once the proxy has
+    /// been dropped.
+    pub fn spawn_health_checks(proxy: &Arc<Proxy>, interval: Duration) -> thread::JoinHandle<()> {
+        let proxy: Weak<Proxy> = Arc::downgrade(proxy);
+
+        thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+
+                match proxy.upgrade() {
+                    Some(proxy) => proxy.check_health(),
+                    None => break,
+                }
+            }
+        })
+    }
+}
+
+fn connect_timeout(addr: &str) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "upstream address didn't resolve");
+
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Rebuild the request head for the upstream: point `Host` at it, drop
+/// hop-by-hop headers and record the original client in the `X-Forwarded-*`
+/// headers.
+fn forwarded_head(request: &Request, upstream: &str, peer: Option<IpAddr>) -> String {
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", request.method, request.path, upstream);
+    let mut forwarded_for = None;
+
+    for (name, value) in request.headers() {
+        let lower = name.to_ascii_lowercase();
+
+        match lower.as_str() {
+            "host" | "x-forwarded-host" | "x-forwarded-proto" => (),
+            "x-forwarded-for" => forwarded_for = Some(value.to_string()),
+            _ if HOP_BY_HOP.contains(&lower.as_str()) => (),
+            _ => head.push_str(&format!("{}: {}\r\n", name, value)),
+        }
+    }
+
+    let forwarded_for = match (forwarded_for, peer) {
+        (Some(previous), Some(peer)) => Some(format!("{}, {}", previous, peer)),
+        (None, Some(peer)) => Some(peer.to_string()),
+        (previous, None) => previous,
+    };
+
+    if let Some(forwarded_for) = forwarded_for {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    }
+
+    if let Some(host) = request.header("Host") {
+        head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+    }
+
+    head.push_str("X-Forwarded-Proto: http\r\nConnection: close\r\n\r\n");
+    head
+}
+
+fn respond<W: Write>(client: &mut W, status: &str) -> io::Result<()> {
+    write!(client, "HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n", status)?;
+    client.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Duplex;
+    use std::net::TcpListener;
+
+    /// A stand-in backend that answers each request with its own name
+    /// followed by the request head and body it received.
+    fn upstream(name: &'static str, requests: usize) -> (String, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut request = Request::read_from(&mut reader).unwrap();
+                request.read_body(&mut reader, 1024).unwrap();
+
+                let mut echo = format!("{}\n", name);
+                for (key, value) in request.headers() {
+                    echo.push_str(&format!("{}: {}\n", key, value));
+                }
+                echo.push_str(&String::from_utf8_lossy(request.body()));
+
+                write!(reader.get_mut(), "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", echo.len(), echo).unwrap();
+            }
+        });
+
+        (addr, handle)
+    }
+
+    fn send(proxy: &Proxy, raw: &str) -> String {
+        let mut reader = BufReader::new(Duplex::new(raw.as_bytes().to_vec()));
+        let request = Request::read_from(&mut reader).unwrap();
+
+        proxy.handle(&request, &mut reader, Some("10.0.0.7".parse().unwrap())).unwrap();
+
+        String::from_utf8(reader.into_inner().output).unwrap()
+    }
+
+    #[test]
+    fn rewrites_headers_and_streams_body() {
+        let (addr, backend) = upstream("one", 1);
+        let proxy = Proxy::new("/api", &[&addr]);
+
+        let response = send(&proxy, "POST /api/items?x=1 HTTP/1.1\r\n\
+Host: example.com\r\n\
+X-Forwarded-For: 192.168.1.1\r\n\
+Connection: keep-alive\r\n\
+Content-Length: 5\r\n\r\n\
+hello");
+        backend.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(&format!("\none\nHost: {}\n", addr)));
+        assert!(response.contains("X-Forwarded-For: 192.168.1.1, 10.0.0.7\n"));
+        assert!(response.contains("X-Forwarded-Host: example.com\n"));
+        assert!(response.contains("Connection: close\n"));
+        assert!(!response.contains("keep-alive"));
+        assert!(response.ends_with("hello"));
+    }
+
+    #[test]
+    fn round_robins_and_skips_dead_upstreams() {
+        let (first, first_backend) = upstream("first", 1);
+        let (second, second_backend) = upstream("second", 2);
+
+        // Grab a free port and close it again so nothing is listening there.
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+
+        let proxy = Proxy::new("/api", &[&first, &dead, &second]);
+        let request = "GET /api HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+        assert!(send(&proxy, request).contains("\nfirst\n"));
+        assert!(send(&proxy, request).contains("\nsecond\n"));
+        assert!(!proxy.is_healthy(&dead));
+        assert!(send(&proxy, request).contains("\nsecond\n"));
+
+        first_backend.join().unwrap();
+        second_backend.join().unwrap();
+    }
+
+    #[test]
+    fn bad_gateway_when_nothing_is_up() {
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let proxy = Proxy::new("/api", &[&dead]);
+
+        let response = send(&proxy, "GET /api HTTP/1.1\r\n\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+    }
+
+    #[test]
+    fn health_checks_use_the_health_path() {
+        let (up, backend) = upstream("up", 1);
+        let proxy = Proxy::new("/api", &[&up]).with_health_path("/health");
+
+        proxy.check_health();
+        backend.join().unwrap();
+        assert!(proxy.is_healthy(&up));
+
+        // The backend has stopped accepting connections now.
+        proxy.check_health();
+        assert!(!proxy.is_healthy(&up));
+    }
+
+    #[test]
+    fn ambiguous_bodies_are_refused_before_connecting() {
+        // Nothing's listening, so getting this far would be a 502.
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let proxy = Proxy::new("/api", &[&dead]);
+
+        let both = "POST /api HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\nhello";
+        assert!(send(&proxy, both).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let plus = "POST /api HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello";
+        assert!(send(&proxy, plus).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let chunked = "POST /api HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+        assert!(send(&proxy, chunked).starts_with("HTTP/1.1 411 Length Required\r\n"));
+    }
+
+    #[test]
+    fn silent_upstreams_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let proxy = Proxy::new("/api", &[&addr]).with_timeout(Duration::from_millis(100));
+
+        let mut reader = BufReader::new(Duplex::new(b"GET /api HTTP/1.1\r\n\r\n".to_vec()));
+        let request = Request::read_from(&mut reader).unwrap();
+
+        // The connection is accepted (by the backlog) but never answered.
+        assert!(proxy.handle(&request, &mut reader, None).is_err());
+        drop(listener);
+    }
+
+    #[test]
+    fn matches_whole_path_segments() {
+        let proxy = Proxy::new("/api", &["127.0.0.1:1"]);
+        let request = |path: &str| {
+            Request::read_from(&mut format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes()).unwrap()
+        };
+
+        assert!(proxy.matches(&request("/api")));
+        assert!(proxy.matches(&request("/api/users?id=1")));
+        assert!(!proxy.matches(&request("/apiary")));
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Once we're tracking this many addresses, forget the ones whose buckets
+/// have refilled completely - they'd start from full again anyway.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+struct Buckets {
+    by_addr: HashMap<IpAddr, Bucket>,
+    /// When we last pruned. A bucket takes `burst / per_second` to refill
+    /// from empty, so pruning more often than that would mostly rescan the
+    /// buckets the last prune kept.
+    pruned: Instant,
+}
+
+/// A token bucket per peer address.
+///
+/// Every address starts with `burst` tokens and regains `per_second` tokens
+/// a second, up to `burst`. Each connection costs one token.
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// # Panics
+    ///
+    /// The `new` function will panic if `per_second` isn't positive or
+    /// `burst` is zero.
+    pub fn new(per_second: f64, burst: u32) -> RateLimiter {
+        assert!(per_second > 0.0);
+        assert!(burst > 0);
+
+        RateLimiter {
+            per_second,
+            burst: f64::from(burst),
+            buckets: Mutex::new(Buckets { by_addr: HashMap::new(), pruned: Instant::now() }),
+        }
+    }
+
+    /// Take a token for `addr`, or say how long until one will be free.
+    pub fn check(&self, addr: IpAddr) -> Result<(), Duration> {
+        self.check_at(addr, Instant::now())
+    }
+
+    fn check_at(&self, addr: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let refill_time = Duration::from_secs_f64(self.burst / self.per_second);
+
+        // However many addresses are still active, each one is only looked
+        // at once per `refill_time`.
+        if buckets.by_addr.len() >= PRUNE_THRESHOLD && now.saturating_duration_since(buckets.pruned) >= refill_time {
+            let (per_second, burst) = (self.per_second, self.burst);
+            buckets.by_addr.retain(|_, bucket| refilled(bucket, now, per_second, burst) < burst);
+            buckets.pruned = now;
+        }
+
+        let bucket = buckets.by_addr.entry(addr).or_insert(Bucket { tokens: self.burst, last: now });
+
+        bucket.tokens = refilled(bucket, now, self.per_second, self.burst);
+        bucket.last = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second))
+        }
+    }
+}
+
+fn refilled(bucket: &Bucket, now: Instant, per_second: f64, burst: f64) -> f64 {
+    let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+
+    (bucket.tokens + elapsed * per_second).min(burst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_a_burst_then_refills_over_time() {
+        let limiter = RateLimiter::new(2.0, 3);
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(addr, start).is_ok());
+        }
+
+        let wait = limiter.check_at(addr, start).unwrap_err();
+        assert_eq!(Duration::from_millis(500), wait);
+
+        assert!(limiter.check_at(addr, start + Duration::from_millis(500)).is_ok());
+        assert!(limiter.check_at(addr, start + Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn addresses_have_separate_buckets() {
+        let limiter = RateLimiter::new(1.0, 1);
+        let now = Instant::now();
+
+        assert!(limiter.check_at("10.0.0.1".parse().unwrap(), now).is_ok());
+        assert!(limiter.check_at("10.0.0.1".parse().unwrap(), now).is_err());
+        assert!(limiter.check_at("10.0.0.2".parse().unwrap(), now).is_ok());
+    }
+
+    #[test]
+    fn prunes_full_buckets_once_per_refill() {
+        // Two tokens a second and room for two, so a bucket used once is
+        // full again after half a second, and we prune at most every second.
+        let limiter = RateLimiter::new(2.0, 2);
+        let start = Instant::now();
+        let tracked = || limiter.buckets.lock().unwrap().by_addr.len();
+
+        for i in 0..PRUNE_THRESHOLD {
+            limiter.check_at(IpAddr::from([10, 0, (i >> 8) as u8, i as u8]), start).unwrap();
+        }
+
+        let second = start + Duration::from_secs(1);
+        limiter.check_at("10.1.0.0".parse().unwrap(), second).unwrap();
+        assert_eq!(1, tracked());
+
+        for i in 1..PRUNE_THRESHOLD {
+            limiter.check_at(IpAddr::from([10, 1, (i >> 8) as u8, i as u8]), second).unwrap();
+        }
+
+        // These are full again, but we only just pruned.
+        let soon = second + Duration::from_millis(600);
+        limiter.check_at("10.2.0.0".parse().unwrap(), soon).unwrap();
+        assert_eq!(PRUNE_THRESHOLD + 1, tracked());
+
+        // Only the bucket that was just used again is still filling up.
+        limiter.check_at("10.2.0.0".parse().unwrap(), second + Duration::from_secs(1)).unwrap();
+        assert_eq!(1, tracked());
+    }
+}
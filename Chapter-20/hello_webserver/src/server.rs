@@ -0,0 +1,308 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::ThreadPool;
+use crate::rate_limit::RateLimiter;
+
+/// How long the accept loop will spend writing a rejection before giving up
+/// on a client, so a slow reader can't stall everyone else.
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Configures a `Server`. Get one from `Server::builder`.
+pub struct ServerBuilder {
+    workers: usize,
+    max_connections: Option<usize>,
+    rate_limit: Option<(f64, u32)>,
+    shutdown_after: Option<usize>,
+}
+
+impl ServerBuilder {
+    /// Number of threads in the pool handling connections. Defaults to 4.
+    pub fn workers(mut self, workers: usize) -> ServerBuilder {
+        self.workers = workers;
+        self
+    }
+
+    /// Cap on connections being handled at once. Connections over the cap
+    /// are answered with `503 Service Unavailable` straight from the accept
+    /// loop instead of queueing for a worker.
+    pub fn max_connections(mut self, max_connections: usize) -> ServerBuilder {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Allow each peer address `burst` connections at once, refilled at
+    /// `per_second`. Peers over their limit get `429 Too Many Requests`.
+    pub fn rate_limit(mut self, per_second: f64, burst: u32) -> ServerBuilder {
+        self.rate_limit = Some((per_second, burst));
+        self
+    }
+
+    /// Stop accepting after this many connections and shut down gracefully
+    /// once they've been handled.
+    pub fn shutdown_after(mut self, connections: usize) -> ServerBuilder {
+        self.shutdown_after = Some(connections);
+        self
+    }
+
+    /// Bind the listener.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of workers is zero, or the rate limit isn't
+    /// positive.
+    pub fn bind<A: ToSocketAddrs>(self, addr: A) -> io::Result<Server> {
+        let listener = TcpListener::bind(addr)?;
+
+        Ok(Server {
+            listener,
+            pool: ThreadPool::new(self.workers),
+            max_connections: self.max_connections,
+            rate_limiter: self.rate_limit.map(|(per_second, burst)| RateLimiter::new(per_second, burst)),
+            shutdown_after: self.shutdown_after,
+            active: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+}
+
+/// A listener feeding connections to a `ThreadPool`, with admission control
+/// in front of it.
+pub struct Server {
+    listener: TcpListener,
+    pool: ThreadPool,
+    max_connections: Option<usize>,
+    rate_limiter: Option<RateLimiter>,
+    shutdown_after: Option<usize>,
+    active: Arc<AtomicUsize>,
+}
+
+/// Counts a connection as active until the worker handling it is done.
+struct ActiveGuard(Arc<AtomicUsize>);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            workers: 4,
+            max_connections: None,
+            rate_limit: None,
+            shutdown_after: None,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept connections and hand the ones we admit to `handler` on the
+    /// pool.
+    ///
+    /// Returns after `shutdown_after` connections (counting rejected ones)
+    /// have been accepted and the workers have finished, or runs forever if
+    /// that wasn't set.
+    pub fn run<F>(self, handler: F)
+    where
+        F: Fn(TcpStream) + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        let incoming = self.listener.incoming().take(self.shutdown_after.unwrap_or(usize::MAX));
+
+        for stream in incoming {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    continue;
+                },
+            };
+
+            if let Some(status) = self.admit(&stream) {
+                reject(stream, status);
+                continue;
+            }
+
+            self.active.fetch_add(1, Ordering::SeqCst);
+            let guard = ActiveGuard(Arc::clone(&self.active));
+            let handler = Arc::clone(&handler);
+
+            self.pool.execute(move || {
+                let _guard = guard;
+                handler(stream);
+            });
+        }
+
+        println!("Shutting down.");
+    }
+
+    /// Decide whether to serve `stream`, returning the rejection's status
+    /// line and headers if not.
+    fn admit(&self, stream: &TcpStream) -> Option<String> {
+        // A connection we turn away for capacity shouldn't cost the client
+        // a token, so check that before the rate limiter takes one.
+        if let Some(max) = self.max_connections {
+            if self.active.load(Ordering::SeqCst) >= max {
+                return Some("503 Service Unavailable\r\nRetry-After: 1".to_string());
+            }
+        }
+
+        if let (Some(limiter), Ok(peer)) = (&self.rate_limiter, stream.peer_addr()) {
+            if let Err(wait) = limiter.check(peer.ip()) {
+                let retry_after = wait.as_secs() + 1;
+                return Some(format!("429 Too Many Requests\r\nRetry-After: {}", retry_after));
+            }
+        }
+
+        None
+    }
+}
+
+fn reject(mut stream: TcpStream, status: String) {
+    let response = format!("HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n", status);
+
+    let _ = stream.set_write_timeout(Some(REJECT_TIMEOUT));
+    let _ = stream.write_all(response.as_bytes());
+    let _ = stream.shutdown(Shutdown::Write);
+
+    // Closing with unread request bytes makes the OS reset the connection,
+    // which can throw away our response. Discard whatever has already
+    // arrived, without waiting for more.
+    if stream.set_nonblocking(true).is_ok() {
+        let mut discard = [0; 4096];
+        while let Ok(n) = stream.read(&mut discard) {
+            if n == 0 {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::sync::Mutex;
+    use std::thread;
+
+    /// Connect and read the status line. We don't send a request since
+    /// the handlers here answer regardless.
+    fn status_of(addr: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        response.lines().next().unwrap_or("").to_string()
+    }
+
+    fn ok_handler(mut stream: TcpStream) {
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn rate_limited_peers_get_429() {
+        let server = Server::builder()
+            .rate_limit(0.01, 2)
+            .shutdown_after(3)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let running = thread::spawn(move || server.run(ok_handler));
+
+        assert_eq!("HTTP/1.1 200 OK", status_of(addr));
+        assert_eq!("HTTP/1.1 200 OK", status_of(addr));
+        assert_eq!("HTTP/1.1 429 Too Many Requests", status_of(addr));
+
+        running.join().unwrap();
+    }
+
+    /// A server whose handler holds each connection's worker until it's
+    /// released, so a test can fill it up.
+    struct Held {
+        addr: SocketAddr,
+        active: Arc<AtomicUsize>,
+        started: mpsc::Receiver<()>,
+        release: mpsc::Sender<()>,
+        running: thread::JoinHandle<()>,
+    }
+
+    fn held(builder: ServerBuilder) -> Held {
+        let server = builder.bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let active = Arc::clone(&server.active);
+
+        let (started_tx, started) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+
+        let running = thread::spawn(move || {
+            server.run(move |stream| {
+                started_tx.send(()).unwrap();
+                release_rx.lock().unwrap().recv().unwrap();
+                ok_handler(stream);
+            })
+        });
+
+        Held { addr, active, started, release, running }
+    }
+
+    impl Held {
+        /// Wait for the workers to be done with every released connection,
+        /// which is a little after its client has seen the response.
+        fn wait_idle(&self) {
+            while self.active.load(Ordering::SeqCst) > 0 {
+                thread::yield_now();
+            }
+        }
+    }
+
+    #[test]
+    fn connections_over_the_cap_get_503() {
+        let server = held(Server::builder().max_connections(1).shutdown_after(2));
+        let addr = server.addr;
+
+        // The first connection holds its worker until we say so.
+        let first = thread::spawn(move || status_of(addr));
+        server.started.recv().unwrap();
+
+        assert_eq!("HTTP/1.1 503 Service Unavailable", status_of(addr));
+
+        server.release.send(()).unwrap();
+        assert_eq!("HTTP/1.1 200 OK", first.join().unwrap());
+
+        server.running.join().unwrap();
+    }
+
+    #[test]
+    fn connections_over_the_cap_keep_their_tokens() {
+        // Two tokens: the first connection spends one, and the one turned
+        // away for capacity shouldn't spend the other.
+        let server = held(Server::builder().max_connections(1).rate_limit(0.01, 2).shutdown_after(4));
+        let addr = server.addr;
+
+        let first = thread::spawn(move || status_of(addr));
+        server.started.recv().unwrap();
+
+        assert_eq!("HTTP/1.1 503 Service Unavailable", status_of(addr));
+
+        server.release.send(()).unwrap();
+        assert_eq!("HTTP/1.1 200 OK", first.join().unwrap());
+        server.wait_idle();
+
+        // So there's still a token for the next connection, but no more.
+        server.release.send(()).unwrap();
+        assert_eq!("HTTP/1.1 200 OK", status_of(addr));
+        server.wait_idle();
+
+        assert_eq!("HTTP/1.1 429 Too Many Requests", status_of(addr));
+
+        server.running.join().unwrap();
+    }
+}
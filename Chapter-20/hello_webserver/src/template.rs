@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use serde_json::Value;
+
+/// How deep `{% include %}`s may nest before we assume there's a cycle.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub enum TemplateError {
+    /// No template with this name was loaded.
+    NotFound(String),
+    Io { path: PathBuf, error: io::Error },
+    Syntax { template: String, message: String },
+    /// Includes nested deeper than `MAX_INCLUDE_DEPTH`, most likely a cycle.
+    IncludeDepth(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::NotFound(name) => write!(f, "no template named {:?}", name),
+            TemplateError::Io { path, error } => write!(f, "couldn't read {}: {}", path.display(), error),
+            TemplateError::Syntax { template, message } => write!(f, "{}: {}", template, message),
+            TemplateError::IncludeDepth(name) => write!(f, "includes nested too deeply in {:?}", name),
+        }
+    }
+}
+
+impl Error for TemplateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TemplateError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed template.
+///
+/// Syntax:
+///
+/// * `{{ user.name }}` inserts a value, HTML escaped.
+/// * `{{{ snippet }}}` inserts a value without escaping.
+/// * `{% if cond %}...{% else %}...{% endif %}`, where `cond` may start with
+///   `not`. Missing values, `null`, `false`, `0`, `""`, `[]` and `{}` are
+///   false.
+/// * `{% for item in items %}...{% endfor %}` loops over an array.
+/// * `{% include "other.html" %}` renders another template in place with the
+///   same variables.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Var { path: Vec<String>, escape: bool },
+    If { path: Vec<String>, negate: bool, then: Vec<Node>, otherwise: Vec<Node> },
+    For { var: String, path: Vec<String>, body: Vec<Node> },
+    Include(String),
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    Var { expr: &'a str, escape: bool },
+    Tag(&'a str),
+}
+
+impl Template {
+    /// Parse template source. `name` is only used in error messages.
+    pub fn parse(name: &str, source: &str) -> Result<Template, TemplateError> {
+        let tokens = tokenize(name, source)?;
+        let mut tokens = tokens.into_iter();
+
+        let (nodes, end) = parse_nodes(name, &mut tokens)?;
+
+        match end {
+            None => Ok(Template { nodes }),
+            Some(tag) => Err(syntax(name, format!("unexpected {{% {} %}}", tag))),
+        }
+    }
+
+    /// Render without support for includes.
+    pub fn render(&self, context: &Value) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        let mut scopes = Vec::new();
+
+        let mut include = |name: &str, _: &Value, _: &[(&str, &Value)]| {
+            Err(TemplateError::NotFound(name.to_string()))
+        };
+
+        render_nodes(&self.nodes, context, &mut scopes, &mut include, &mut out)?;
+
+        Ok(out)
+    }
+}
+
+fn syntax(template: &str, message: String) -> TemplateError {
+    TemplateError::Syntax { template: template.to_string(), message }
+}
+
+fn tokenize<'a>(name: &str, mut source: &'a str) -> Result<Vec<Token<'a>>, TemplateError> {
+    let mut tokens = Vec::new();
+
+    while let Some(start) = source.find('{') {
+        let rest = &source[start..];
+
+        let (open, close) = if rest.starts_with("{{{") {
+            ("{{{", "}}}")
+        } else if rest.starts_with("{{") {
+            ("{{", "}}")
+        } else if rest.starts_with("{%") {
+            ("{%", "%}")
+        } else {
+            tokens.push(Token::Text(&source[..start + 1]));
+            source = &source[start + 1..];
+            continue;
+        };
+
+        if start > 0 {
+            tokens.push(Token::Text(&source[..start]));
+        }
+
+        let inner = &rest[open.len()..];
+        let end = match inner.find(close) {
+            Some(end) => end,
+            None => return Err(syntax(name, format!("unclosed {}", open))),
+        };
+        let expr = inner[..end].trim();
+
+        tokens.push(match open {
+            "{{{" => Token::Var { expr, escape: false },
+            "{{" => Token::Var { expr, escape: true },
+            _ => Token::Tag(expr),
+        });
+
+        source = &inner[end + close.len()..];
+    }
+
+    if !source.is_empty() {
+        tokens.push(Token::Text(source));
+    }
+
+    Ok(tokens)
+}
+
+/// Parse nodes until the tokens run out or we hit a tag that closes the
+/// enclosing block, which is handed back to the caller.
+fn parse_nodes<'a, I>(name: &str, tokens: &mut I) -> Result<(Vec<Node>, Option<&'a str>), TemplateError>
+where
+    I: Iterator<Item = Token<'a>>,
+{
+    let mut nodes = Vec::new();
+
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Text(text) => match nodes.last_mut() {
+                Some(Node::Text(previous)) => previous.push_str(text),
+                _ => nodes.push(Node::Text(text.to_string())),
+            },
+            Token::Var { expr, escape } => nodes.push(Node::Var { path: parse_path(name, expr)?, escape }),
+            Token::Tag(tag) => {
+                let words: Vec<&str> = tag.split_whitespace().collect();
+
+                match words.as_slice() {
+                    ["if", "not", cond] | ["if", cond] => {
+                        let negate = words.len() == 3;
+                        let path = parse_path(name, cond)?;
+
+                        let (then, end) = parse_nodes(name, tokens)?;
+                        let otherwise = match end {
+                            Some("else") => match parse_nodes(name, tokens)? {
+                                (otherwise, Some("endif")) => otherwise,
+                                _ => return Err(syntax(name, "{% else %} without {% endif %}".to_string())),
+                            },
+                            Some("endif") => Vec::new(),
+                            _ => return Err(syntax(name, "{% if %} without {% endif %}".to_string())),
+                        };
+
+                        nodes.push(Node::If { path, negate, then, otherwise });
+                    },
+                    ["for", var, "in", list] => {
+                        let path = parse_path(name, list)?;
+
+                        let body = match parse_nodes(name, tokens)? {
+                            (body, Some("endfor")) => body,
+                            _ => return Err(syntax(name, "{% for %} without {% endfor %}".to_string())),
+                        };
+
+                        nodes.push(Node::For { var: var.to_string(), path, body });
+                    },
+                    ["include", file] if file.len() > 1 && file.starts_with('"') && file.ends_with('"') => {
+                        nodes.push(Node::Include(file.trim_matches('"').to_string()));
+                    },
+                    ["else"] | ["endif"] | ["endfor"] => return Ok((nodes, Some(tag))),
+                    _ => return Err(syntax(name, format!("unknown tag {{% {} %}}", tag))),
+                }
+            },
+        }
+    }
+
+    Ok((nodes, None))
+}
+
+fn parse_path(name: &str, expr: &str) -> Result<Vec<String>, TemplateError> {
+    let path: Vec<String> = expr.split('.').map(|segment| segment.to_string()).collect();
+
+    let valid = path.iter().all(|segment| {
+        !segment.is_empty() && segment.chars().all(|c| c.is_alphanumeric() || c == '_')
+    });
+
+    if valid {
+        Ok(path)
+    } else {
+        Err(syntax(name, format!("bad variable {:?}", expr)))
+    }
+}
+
+/// Renders the named include given the current variables and loop scopes.
+type IncludeFn<'r> = dyn FnMut(&str, &Value, &[(&str, &Value)]) -> Result<String, TemplateError> + 'r;
+
+fn render_nodes<'v>(
+    nodes: &'v [Node],
+    context: &'v Value,
+    scopes: &mut Vec<(&'v str, &'v Value)>,
+    include: &mut IncludeFn,
+    out: &mut String,
+) -> Result<(), TemplateError> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var { path, escape } => {
+                let text = lookup(path, context, scopes).map(display).unwrap_or_default();
+
+                if *escape {
+                    escape_html(&text, out);
+                } else {
+                    out.push_str(&text);
+                }
+            },
+            Node::If { path, negate, then, otherwise } => {
+                if truthy(lookup(path, context, scopes)) != *negate {
+                    render_nodes(then, context, scopes, include, out)?;
+                } else {
+                    render_nodes(otherwise, context, scopes, include, out)?;
+                }
+            },
+            Node::For { var, path, body } => {
+                if let Some(Value::Array(items)) = lookup(path, context, scopes) {
+                    for item in items {
+                        scopes.push((var, item));
+                        let result = render_nodes(body, context, scopes, include, out);
+                        scopes.pop();
+                        result?;
+                    }
+                }
+            },
+            Node::Include(name) => out.push_str(&include(name, context, scopes)?),
+        }
+    }
+
+    Ok(())
+}
+
+fn lookup<'v>(path: &[String], context: &'v Value, scopes: &[(&str, &'v Value)]) -> Option<&'v Value> {
+    let (first, rest) = path.split_first()?;
+
+    let mut value = scopes.iter()
+        .rev()
+        .find(|(name, _)| name == first)
+        .map(|(_, value)| *value)
+        .or_else(|| context.get(first))?;
+
+    for segment in rest {
+        value = match value {
+            Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+            _ => value.get(segment)?,
+        };
+    }
+
+    Some(value)
+}
+
+fn truthy(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(b)) => *b,
+        Some(Value::Number(n)) => n.as_f64() != Some(0.0),
+        Some(Value::String(s)) => !s.is_empty(),
+        Some(Value::Array(items)) => !items.is_empty(),
+        Some(Value::Object(map)) => !map.is_empty(),
+    }
+}
+
+fn display(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Append `text` to `out` with the characters that matter in HTML (including
+/// inside quoted attributes) escaped.
+pub fn escape_html(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+struct Cached {
+    template: Arc<Template>,
+    modified: Option<SystemTime>,
+}
+
+/// Every template in a directory, parsed once and shared between workers.
+///
+/// In dev mode (see `set_hot_reload`) each render checks the file's
+/// modification time and re-parses it if it changed on disk, so edits show
+/// up without restarting the server.
+pub struct Templates {
+    dir: PathBuf,
+    hot_reload: bool,
+    cache: RwLock<HashMap<String, Cached>>,
+}
+
+impl Templates {
+    /// Parse every `.html` file in `dir`.
+    ///
+    /// Any file that fails to read or parse fails the whole load, so
+    /// mistakes show up at startup rather than on the first request.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Templates, TemplateError> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut cache = HashMap::new();
+
+        let entries = fs::read_dir(&dir).map_err(|error| TemplateError::Io { path: dir.clone(), error })?;
+
+        for entry in entries {
+            let path = entry.map_err(|error| TemplateError::Io { path: dir.clone(), error })?.path();
+
+            if path.extension().and_then(|ext| ext.to_str()) != Some("html") {
+                continue;
+            }
+
+            if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+                cache.insert(name.to_string(), read_template(&path, name)?);
+            }
+        }
+
+        Ok(Templates { dir, hot_reload: false, cache: RwLock::new(cache) })
+    }
+
+    /// Turn dev mode hot reloading on or off.
+    pub fn set_hot_reload(&mut self, hot_reload: bool) {
+        self.hot_reload = hot_reload;
+    }
+
+    /// Render the template called `name` (its file name, e.g. `hello.html`).
+    pub fn render(&self, name: &str, context: &Value) -> Result<String, TemplateError> {
+        self.render_at_depth(name, context, &[], 0)
+    }
+
+    fn render_at_depth(
+        &self,
+        name: &str,
+        context: &Value,
+        scopes: &[(&str, &Value)],
+        depth: usize,
+    ) -> Result<String, TemplateError> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(TemplateError::IncludeDepth(name.to_string()));
+        }
+
+        let template = self.get(name)?;
+
+        let mut out = String::new();
+        let mut scopes = scopes.to_vec();
+        let mut include = |name: &str, context: &Value, scopes: &[(&str, &Value)]| {
+            self.render_at_depth(name, context, scopes, depth + 1)
+        };
+
+        render_nodes(&template.nodes, context, &mut scopes, &mut include, &mut out)?;
+
+        Ok(out)
+    }
+
+    fn get(&self, name: &str) -> Result<Arc<Template>, TemplateError> {
+        if self.hot_reload {
+            self.reload_if_changed(name)?;
+        }
+
+        match self.cache.read().unwrap().get(name) {
+            Some(cached) => Ok(Arc::clone(&cached.template)),
+            None => Err(TemplateError::NotFound(name.to_string())),
+        }
+    }
+
+    fn reload_if_changed(&self, name: &str) -> Result<(), TemplateError> {
+        // Only plain file names inside our directory, never paths out of it.
+        if name.contains('/') || name.contains('\\') || name.starts_with('.') {
+            return Err(TemplateError::NotFound(name.to_string()));
+        }
+
+        let path = self.dir.join(name);
+        let modified = fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+
+        let stale = match self.cache.read().unwrap().get(name) {
+            Some(cached) => modified.is_some() && cached.modified != modified,
+            None => modified.is_some(),
+        };
+
+        if stale {
+            let fresh = read_template(&path, name)?;
+            self.cache.write().unwrap().insert(name.to_string(), fresh);
+        }
+
+        Ok(())
+    }
+}
+
+fn read_template(path: &Path, name: &str) -> Result<Cached, TemplateError> {
+    let io_error = |error| TemplateError::Io { path: path.to_path_buf(), error };
+
+    let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    let source = fs::read_to_string(path).map_err(io_error)?;
+
+    Ok(Cached { template: Arc::new(Template::parse(name, &source)?), modified })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(source: &str, context: Value) -> String {
+        Template::parse("test", source).unwrap().render(&context).unwrap()
+    }
+
+    #[test]
+    fn interpolation_is_escaped_unless_triple_braced() {
+        let context = json!({ "name": "<b>Ferris</b>" });
+
+        assert_eq!(
+            "&lt;b&gt;Ferris&lt;/b&gt; <b>Ferris</b>",
+            render("{{ name }} {{{ name }}}", context)
+        );
+    }
+
+    #[test]
+    fn loops_and_conditionals_nest() {
+        let context = json!({
+            "user": { "name": "Peter" },
+            "langs": [{ "name": "Rust", "fav": true }, { "name": "Go", "fav": false }],
+        });
+
+        assert_eq!(
+            "Hi Peter: Rust*, Go, ",
+            render(
+                "{% if user %}Hi {{ user.name }}{% else %}Hi stranger{% endif %}: \
+{% for lang in langs %}{{ lang.name }}{% if lang.fav %}*{% endif %}, {% endfor %}",
+                context,
+            )
+        );
+    }
+
+    #[test]
+    fn missing_values_are_empty_and_false() {
+        assert_eq!("[] nope", render("[{{ missing.deep }}] {% if not missing %}nope{% endif %}", json!({})));
+    }
+
+    #[test]
+    fn unbalanced_blocks_are_syntax_errors() {
+        assert!(Template::parse("t", "{% if x %}never closed").is_err());
+        assert!(Template::parse("t", "{% endfor %}").is_err());
+        assert!(Template::parse("t", "{{ not closed").is_err());
+    }
+
+    #[test]
+    fn includes_share_loop_variables_and_hot_reload() {
+        let dir = std::env::temp_dir().join(format!("hello_webserver_templates_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("page.html"), "{% for x in xs %}{% include \"item.html\" %}{% endfor %}").unwrap();
+        fs::write(dir.join("item.html"), "<{{ x }}>").unwrap();
+
+        let mut templates = Templates::load(&dir).unwrap();
+        assert_eq!("<1><2>", templates.render("page.html", &json!({ "xs": [1, 2] })).unwrap());
+
+        templates.set_hot_reload(true);
+        fs::write(dir.join("item.html"), "({{ x }})").unwrap();
+        // Coarse filesystem timestamps could otherwise hide the edit.
+        let modified = SystemTime::now() + std::time::Duration::from_secs(1);
+        fs::File::options().write(true).open(dir.join("item.html")).unwrap().set_modified(modified).unwrap();
+
+        assert_eq!("(1)(2)", templates.render("page.html", &json!({ "xs": [1, 2] })).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_cycles_are_caught() {
+        let dir = std::env::temp_dir().join(format!("hello_webserver_cycle_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("loop.html"), "{% include \"loop.html\" %}").unwrap();
+
+        let templates = Templates::load(&dir).unwrap();
+
+        match templates.render("loop.html", &json!({})) {
+            Err(TemplateError::IncludeDepth(_)) => (),
+            other => panic!("expected IncludeDepth, got {:?}", other),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
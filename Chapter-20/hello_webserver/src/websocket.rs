@@ -0,0 +1,567 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufReader, Read, Write};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sha1::{Digest, Sha1};
+
+use crate::http::Request;
+
+/// Magic value from RFC 6455 that gets appended to the client's key before
+/// hashing it into `Sec-WebSocket-Accept`.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Messages (after reassembling fragments) larger than this are refused so a
+/// single client can't make a worker allocate without bound.
+const MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+
+/// Close codes we send ourselves.
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_u8(bits: u8) -> Option<Opcode> {
+        match bits {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+/// A single frame on the wire.
+///
+/// `payload` is always stored unmasked. When reading, `mask` records the key
+/// the peer used; when writing, a `Some` mask makes `write_to` mask the
+/// payload with that key (clients must do this, servers must not).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub mask: Option<[u8; 4]>,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(opcode: Opcode, payload: Vec<u8>) -> Frame {
+        Frame { fin: true, opcode, mask: None, payload }
+    }
+
+    /// Read one frame, unmasking its payload.
+    ///
+    /// Frames longer than `max_len` are rejected with `InvalidData` before
+    /// their payload is read.
+    pub fn read_from<R: Read>(reader: &mut R, max_len: usize) -> io::Result<Frame> {
+        let mut head = [0; 2];
+        reader.read_exact(&mut head)?;
+
+        let fin = head[0] & 0x80 != 0;
+
+        if head[0] & 0x70 != 0 {
+            return Err(invalid("reserved bits set without an extension"));
+        }
+
+        let opcode = match Opcode::from_u8(head[0] & 0x0F) {
+            Some(opcode) => opcode,
+            None => return Err(invalid("unknown opcode")),
+        };
+
+        let masked = head[1] & 0x80 != 0;
+
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                reader.read_exact(&mut len)?;
+                u64::from(u16::from_be_bytes(len))
+            },
+            127 => {
+                let mut len = [0; 8];
+                reader.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            },
+            len => u64::from(len),
+        };
+
+        if opcode.is_control() && (!fin || len > 125) {
+            return Err(invalid("control frames must be short and unfragmented"));
+        }
+
+        if len > max_len as u64 {
+            return Err(too_big());
+        }
+
+        let mask = if masked {
+            let mut key = [0; 4];
+            reader.read_exact(&mut key)?;
+            Some(key)
+        } else {
+            None
+        };
+
+        let mut payload = vec![0; len as usize];
+        reader.read_exact(&mut payload)?;
+
+        if let Some(key) = mask {
+            apply_mask(&mut payload, key);
+        }
+
+        Ok(Frame { fin, opcode, mask, payload })
+    }
+
+    /// Write this frame, masking the payload if `mask` is set.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut head = Vec::with_capacity(14);
+
+        let fin = if self.fin { 0x80 } else { 0 };
+        head.push(fin | self.opcode.as_u8());
+
+        let masked = if self.mask.is_some() { 0x80 } else { 0 };
+        let len = self.payload.len();
+
+        if len < 126 {
+            head.push(masked | len as u8);
+        } else if len <= usize::from(u16::MAX) {
+            head.push(masked | 126);
+            head.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            head.push(masked | 127);
+            head.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+
+        writer.write_all(&head)?;
+
+        match self.mask {
+            Some(key) => {
+                let mut payload = self.payload.clone();
+                apply_mask(&mut payload, key);
+                writer.write_all(&key)?;
+                writer.write_all(&payload)?;
+            },
+            None => writer.write_all(&self.payload)?,
+        }
+
+        writer.flush()
+    }
+}
+
+fn apply_mask(payload: &mut [u8], key: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= key[i % 4];
+    }
+}
+
+/// A complete message as seen by a handler.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<u16>),
+}
+
+/// Is this request asking to be upgraded to a WebSocket?
+pub fn is_upgrade(request: &Request) -> bool {
+    request.method == "GET"
+        && request.header_contains("Upgrade", "websocket")
+        && request.header_contains("Connection", "upgrade")
+}
+
+/// Compute the `Sec-WebSocket-Accept` value for a client's
+/// `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(GUID.as_bytes());
+
+    STANDARD.encode(hasher.finalize())
+}
+
+/// Complete the opening handshake for `request` and hand back the socket.
+///
+/// `reader` must be the same buffered reader the request was parsed from so
+/// that no bytes the client sent early are lost. If the request isn't a valid
+/// version 13 upgrade a `400 Bad Request` is written and an `InvalidData`
+/// error is returned.
+pub fn accept<S: Read + Write>(mut reader: BufReader<S>, request: &Request) -> io::Result<WebSocket<S>> {
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) if is_upgrade(request) && request.header("Sec-WebSocket-Version") == Some("13") => key,
+        _ => {
+            let stream = reader.get_mut();
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\nSec-WebSocket-Version: 13\r\nContent-Length: 0\r\n\r\n")?;
+            stream.flush()?;
+
+            return Err(invalid("not a websocket upgrade request"));
+        },
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+Upgrade: websocket\r\n\
+Connection: Upgrade\r\n\
+Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key),
+    );
+
+    let stream = reader.get_mut();
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
+
+    Ok(WebSocket::from_reader(reader))
+}
+
+/// The server side of an open WebSocket connection.
+///
+/// Iterating over a `WebSocket` yields every message the client sends until
+/// the close handshake finishes. Pings are answered automatically, but are
+/// still passed on in case the handler cares about them.
+pub struct WebSocket<S> {
+    stream: BufReader<S>,
+    max_message_len: usize,
+    fragments: Option<(Opcode, Vec<u8>)>,
+    sent_close: bool,
+    received_close: bool,
+}
+
+impl<S: Read + Write> WebSocket<S> {
+    /// Wrap a stream that has already completed the handshake.
+    pub fn from_reader(stream: BufReader<S>) -> WebSocket<S> {
+        WebSocket {
+            stream,
+            max_message_len: MAX_MESSAGE_LEN,
+            fragments: None,
+            sent_close: false,
+            received_close: false,
+        }
+    }
+
+    /// Set the largest message `recv` will reassemble.
+    pub fn set_max_message_len(&mut self, max_message_len: usize) {
+        self.max_message_len = max_message_len;
+    }
+
+    /// Wait for the next message from the client.
+    ///
+    /// Returns `Ok(None)` once the client's close frame has been received
+    /// and answered. Protocol violations close the connection with the
+    /// matching status code and are returned as `InvalidData` errors.
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        if self.received_close {
+            return Ok(None);
+        }
+
+        match self.read_message() {
+            Err(e) => {
+                let violation = e.get_ref().and_then(|inner| inner.downcast_ref::<ProtocolError>());
+
+                if let Some(violation) = violation {
+                    // The peer is misbehaving; we don't care if this fails.
+                    let _ = self.close(violation.code);
+                }
+
+                self.received_close = true;
+                Err(e)
+            },
+            result => result.map(Some),
+        }
+    }
+
+    fn read_message(&mut self) -> io::Result<Message> {
+        loop {
+            let frame = Frame::read_from(&mut self.stream, self.max_message_len)?;
+
+            if frame.mask.is_none() {
+                return Err(invalid("client frames must be masked"));
+            }
+
+            match frame.opcode {
+                Opcode::Ping => {
+                    if !self.sent_close {
+                        Frame::new(Opcode::Pong, frame.payload.clone()).write_to(self.stream.get_mut())?;
+                    }
+
+                    return Ok(Message::Ping(frame.payload));
+                },
+                Opcode::Pong => return Ok(Message::Pong(frame.payload)),
+                Opcode::Close => {
+                    let code = match frame.payload.len() {
+                        0 => None,
+                        1 => return Err(invalid("truncated close frame")),
+                        _ => Some(u16::from_be_bytes([frame.payload[0], frame.payload[1]])),
+                    };
+
+                    // never echo a code that isn't allowed on the wire
+                    if code.is_some_and(|code| !is_valid_close_code(code)) {
+                        return Err(invalid("invalid close code"));
+                    }
+
+                    self.received_close = true;
+
+                    if !self.sent_close {
+                        self.close(code.unwrap_or(CLOSE_NORMAL))?;
+                    }
+
+                    return Ok(Message::Close(code));
+                },
+                Opcode::Text | Opcode::Binary => {
+                    if self.fragments.is_some() {
+                        return Err(invalid("new message started inside a fragmented one"));
+                    }
+
+                    if frame.fin {
+                        return into_message(frame.opcode, frame.payload);
+                    }
+
+                    self.fragments = Some((frame.opcode, frame.payload));
+                },
+                Opcode::Continuation => {
+                    let (opcode, mut data) = match self.fragments.take() {
+                        Some(partial) => partial,
+                        None => return Err(invalid("continuation frame without a message")),
+                    };
+
+                    if data.len() + frame.payload.len() > self.max_message_len {
+                        return Err(too_big());
+                    }
+
+                    data.extend_from_slice(&frame.payload);
+
+                    if frame.fin {
+                        return into_message(opcode, data);
+                    }
+
+                    self.fragments = Some((opcode, data));
+                },
+            }
+        }
+    }
+
+    /// Send a message to the client as a single unmasked frame.
+    pub fn send(&mut self, message: Message) -> io::Result<()> {
+        let frame = match message {
+            Message::Text(text) => Frame::new(Opcode::Text, text.into_bytes()),
+            Message::Binary(data) => Frame::new(Opcode::Binary, data),
+            Message::Ping(data) => Frame::new(Opcode::Ping, data),
+            Message::Pong(data) => Frame::new(Opcode::Pong, data),
+            Message::Close(code) => return self.close(code.unwrap_or(CLOSE_NORMAL)),
+        };
+
+        frame.write_to(self.stream.get_mut())
+    }
+
+    /// Start (or finish) the close handshake with `code`.
+    ///
+    /// Calling this more than once only sends a single close frame.
+    pub fn close(&mut self, code: u16) -> io::Result<()> {
+        if self.sent_close {
+            return Ok(());
+        }
+
+        self.sent_close = true;
+
+        Frame::new(Opcode::Close, code.to_be_bytes().to_vec()).write_to(self.stream.get_mut())
+    }
+
+    /// Get at the underlying stream, e.g. to set timeouts.
+    pub fn get_ref(&self) -> &S {
+        self.stream.get_ref()
+    }
+}
+
+impl<S: Read + Write> Iterator for WebSocket<S> {
+    type Item = io::Result<Message>;
+
+    fn next(&mut self) -> Option<io::Result<Message>> {
+        self.recv().transpose()
+    }
+}
+
+fn into_message(opcode: Opcode, payload: Vec<u8>) -> io::Result<Message> {
+    match opcode {
+        Opcode::Text => String::from_utf8(payload)
+            .map(Message::Text)
+            .map_err(|_| bad_utf8()),
+        _ => Ok(Message::Binary(payload)),
+    }
+}
+
+/// A client broke the protocol. Carried inside an `InvalidData` `io::Error`
+/// so `recv` knows which close code to send back.
+#[derive(Debug)]
+struct ProtocolError {
+    code: u16,
+    reason: &'static str,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (close code {})", self.reason, self.code)
+    }
+}
+
+impl Error for ProtocolError {}
+
+// Close codes a peer may send (RFC 6455 section 7.4). 1005, 1006 and 1015 are
+// reserved for reporting locally, and the rest below 3000 are unassigned.
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+fn protocol_error(code: u16, reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, ProtocolError { code, reason })
+}
+
+fn invalid(reason: &'static str) -> io::Error {
+    protocol_error(CLOSE_PROTOCOL_ERROR, reason)
+}
+
+fn too_big() -> io::Error {
+    protocol_error(CLOSE_TOO_BIG, "message too big")
+}
+
+fn bad_utf8() -> io::Error {
+    protocol_error(CLOSE_INVALID_DATA, "text message is not valid UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Duplex;
+
+    fn client_frames(frames: &[Frame]) -> WebSocket<Duplex> {
+        let mut input = Vec::new();
+        for frame in frames {
+            frame.write_to(&mut input).unwrap();
+        }
+
+        WebSocket::from_reader(BufReader::new(Duplex::new(input)))
+    }
+
+    fn masked(fin: bool, opcode: Opcode, payload: &[u8]) -> Frame {
+        Frame { fin, opcode, mask: Some([1, 2, 3, 4]), payload: payload.to_vec() }
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        assert_eq!(
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+            accept_key("dGhlIHNhbXBsZSBub25jZQ==")
+        );
+    }
+
+    #[test]
+    fn handshake_writes_switching_protocols() {
+        let mut raw: &[u8] = b"GET /ws HTTP/1.1\r\n\
+Host: localhost\r\n\
+Upgrade: websocket\r\n\
+Connection: Upgrade\r\n\
+Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+Sec-WebSocket-Version: 13\r\n\r\n";
+        let request = Request::read_from(&mut raw).unwrap();
+
+        let stream = Duplex::new(Vec::new());
+        let socket = accept(BufReader::new(stream), &request).unwrap();
+        let response = String::from_utf8(socket.get_ref().output.clone()).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[test]
+    fn frame_round_trips_with_extended_lengths() {
+        for &len in &[0, 125, 126, 65535, 65536] {
+            let frame = masked(true, Opcode::Binary, &vec![7; len]);
+            let mut wire = Vec::new();
+            frame.write_to(&mut wire).unwrap();
+
+            let decoded = Frame::read_from(&mut &wire[..], MAX_MESSAGE_LEN).unwrap();
+
+            assert_eq!(frame, decoded);
+        }
+    }
+
+    #[test]
+    fn reassembles_fragments_around_a_ping() {
+        let mut socket = client_frames(&[
+            masked(false, Opcode::Text, b"Hel"),
+            masked(true, Opcode::Ping, b"hi"),
+            masked(true, Opcode::Continuation, b"lo"),
+        ]);
+
+        assert_eq!(Message::Ping(b"hi".to_vec()), socket.recv().unwrap().unwrap());
+        assert_eq!(Message::Text("Hello".to_string()), socket.recv().unwrap().unwrap());
+
+        let pong = Frame::read_from(&mut &socket.get_ref().output[..], 125).unwrap();
+        assert_eq!(Frame::new(Opcode::Pong, b"hi".to_vec()), pong);
+    }
+
+    #[test]
+    fn close_is_echoed_and_ends_the_stream() {
+        let socket = client_frames(&[
+            masked(true, Opcode::Binary, &[1, 2, 3]),
+            masked(true, Opcode::Close, &1000u16.to_be_bytes()),
+            masked(true, Opcode::Text, b"ignored"),
+        ]);
+
+        let messages: Vec<Message> = socket.map(Result::unwrap).collect();
+
+        assert_eq!(
+            vec![Message::Binary(vec![1, 2, 3]), Message::Close(Some(1000))],
+            messages
+        );
+    }
+
+    #[test]
+    fn reserved_close_codes_are_a_protocol_error() {
+        for &code in &[999u16, 1005, 1006, 1015, 2999, 5000] {
+            let mut socket = client_frames(&[masked(true, Opcode::Close, &code.to_be_bytes())]);
+
+            assert!(socket.recv().is_err(), "close code {}", code);
+
+            let close = Frame::read_from(&mut &socket.get_ref().output[..], 125).unwrap();
+            assert_eq!(CLOSE_PROTOCOL_ERROR.to_be_bytes().to_vec(), close.payload);
+        }
+    }
+
+    #[test]
+    fn unmasked_client_frame_is_a_protocol_error() {
+        let mut socket = client_frames(&[Frame::new(Opcode::Text, b"nope".to_vec())]);
+
+        assert!(socket.recv().is_err());
+
+        let close = Frame::read_from(&mut &socket.get_ref().output[..], 125).unwrap();
+        assert_eq!(CLOSE_PROTOCOL_ERROR.to_be_bytes().to_vec(), close.payload);
+    }
+}